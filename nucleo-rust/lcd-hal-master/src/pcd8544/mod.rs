@@ -27,6 +27,9 @@ pub trait Pcd8544 {
 
     fn set_mode(&mut self, mode: Modes) -> Result<(), Self::Error>;
 
+    /// power_down = true => display blanked, RAM kept; any function set command wakes it up again
+    fn set_power_down(&mut self, power_down: bool) -> Result<(), Self::Error>;
+
     fn init(&mut self) -> Result<(), Self::Error>;
 
     // note: data direction is vertical: [1 2 3 4 5 6]
@@ -111,6 +114,10 @@ impl<T: Pcd8544Base> Pcd8544 for T {
         self.command(mode as u8) // set display control to normal mode: 0x0D for inverse
     }
 
+    fn set_power_down(&mut self, power_down: bool) -> Result<(), Self::Error> {
+        self.command(0b0010_0000 | if power_down { 0b100u8 } else { 0u8 })
+    }
+
     fn init(&mut self) -> Result<(), Self::Error> {
         self.set_lcd_coefficients(56, 0, 4)?;
         self.set_mode(Modes::Normal)?;
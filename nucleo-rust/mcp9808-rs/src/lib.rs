@@ -0,0 +1,186 @@
+#![deny(unsafe_code)]
+#![no_std]
+
+use embedded_hal::blocking::i2c::{Write, WriteRead};
+
+/// address with A2..A0 tied low
+pub const DEFAULT_ADDRESS: u8 = 0x18;
+pub const MANUFACTURER_ID: u16 = 0x0054;
+pub const DEVICE_ID: u8 = 0x04;
+
+#[derive(Copy, Clone)]
+enum Register {
+    Configuration = 0x01,
+    UpperLimit = 0x02,
+    LowerLimit = 0x03,
+    CriticalLimit = 0x04,
+    AmbientTemperature = 0x05,
+    ManufacturerId = 0x06,
+    DeviceId = 0x07,
+    Resolution = 0x08,
+}
+
+/// Conversion resolution; finer steps take longer: 30, 65, 130 and 250 ms
+#[derive(Copy, Clone, PartialEq)]
+pub enum Resolution {
+    Half = 0,
+    Quarter = 1,
+    Eighth = 2,
+    Sixteenth = 3,
+}
+
+impl Resolution {
+    pub fn from_bits(bits: u8) -> Resolution {
+        match bits & 0b11 {
+            0 => Resolution::Half,
+            1 => Resolution::Quarter,
+            2 => Resolution::Eighth,
+            _ => Resolution::Sixteenth,
+        }
+    }
+
+    /// step in ten-thousandths of a degree
+    pub fn step(&self) -> u16 {
+        match self {
+            Resolution::Half => 5000,
+            Resolution::Quarter => 2500,
+            Resolution::Eighth => 1250,
+            Resolution::Sixteenth => 625,
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+pub enum Limit {
+    Upper,
+    Lower,
+    Critical,
+}
+
+impl Limit {
+    fn register(&self) -> Register {
+        match self {
+            Limit::Upper => Register::UpperLimit,
+            Limit::Lower => Register::LowerLimit,
+            Limit::Critical => Register::CriticalLimit,
+        }
+    }
+}
+
+/// Window comparison flags latched with every temperature conversion
+#[derive(Copy, Clone, Default, PartialEq)]
+pub struct Alert {
+    /// temperature >= critical limit
+    pub critical: bool,
+    /// temperature > upper limit
+    pub upper: bool,
+    /// temperature < lower limit
+    pub lower: bool,
+}
+
+#[derive(Copy, Clone)]
+pub struct Temperature {
+    /// centi-degrees Celsius, truncated from the 1/16 degree register value
+    pub centi: i16,
+    pub alert: Alert,
+}
+
+pub struct Mcp9808<I2C> {
+    i2c: I2C,
+    address: u8,
+}
+
+impl<I2C, E> Mcp9808<I2C>
+where
+    I2C: Write<Error = E> + WriteRead<Error = E>,
+{
+    pub fn new(i2c: I2C, address: u8) -> Mcp9808<I2C> {
+        Mcp9808 { i2c, address }
+    }
+
+    pub fn release(self) -> I2C {
+        self.i2c
+    }
+
+    pub fn manufacturer_id(&mut self) -> Result<u16, E> {
+        self.read_word(Register::ManufacturerId)
+    }
+
+    /// returns (device id, revision)
+    pub fn device_id(&mut self) -> Result<(u8, u8), E> {
+        let word = self.read_word(Register::DeviceId)?;
+        Ok(((word >> 8) as u8, word as u8))
+    }
+
+    pub fn temperature(&mut self) -> Result<Temperature, E> {
+        let word = self.read_word(Register::AmbientTemperature)?;
+        let alert = Alert {
+            critical: word & 0x8000 != 0,
+            upper: word & 0x4000 != 0,
+            lower: word & 0x2000 != 0,
+        };
+        //13 bit two's complement in 1/16 degree
+        let mut sixteenths = (word & 0x0FFF) as i32;
+        if word & 0x1000 != 0 {
+            sixteenths -= 0x1000;
+        }
+        Ok(Temperature { centi: (sixteenths * 25 / 4) as i16, alert })
+    }
+
+    pub fn resolution(&mut self) -> Result<Resolution, E> {
+        let mut buffer = [0u8; 1];
+        self.i2c.write_read(self.address, &[Register::Resolution as u8], &mut buffer)?;
+        Ok(Resolution::from_bits(buffer[0]))
+    }
+
+    pub fn set_resolution(&mut self, resolution: Resolution) -> Result<(), E> {
+        self.i2c.write(self.address, &[Register::Resolution as u8, resolution as u8])
+    }
+
+    /// centi-degrees Celsius, stored with 0.25 degree granularity
+    pub fn limit(&mut self, limit: Limit) -> Result<i16, E> {
+        let word = self.read_word(limit.register())?;
+        let mut quarters = ((word & 0x0FFC) >> 2) as i32;
+        if word & 0x1000 != 0 {
+            quarters -= 0x400;
+        }
+        Ok((quarters * 25) as i16)
+    }
+
+    /// centi-degrees Celsius, rounded towards zero to 0.25 degree;
+    /// fails silently on the device if the window or critical lock bits are set
+    pub fn set_limit(&mut self, limit: Limit, centi: i16) -> Result<(), E> {
+        let quarters = centi as i32 / 25;
+        self.write_word(limit.register(), ((quarters << 2) as u16) & 0x1FFC)
+    }
+
+    /// Enables the open-drain alert output in comparator mode, active low,
+    /// asserted on both window and critical violations
+    pub fn enable_alert(&mut self) -> Result<(), E> {
+        let config = self.read_word(Register::Configuration)?;
+        self.write_word(Register::Configuration, (config & !0b0111) | 0b1000)
+    }
+
+    pub fn disable_alert(&mut self) -> Result<(), E> {
+        let config = self.read_word(Register::Configuration)?;
+        self.write_word(Register::Configuration, config & !0b1000)
+    }
+
+    /// power down between readings; the last conversion stays readable
+    pub fn shutdown(&mut self, shutdown: bool) -> Result<(), E> {
+        let config = self.read_word(Register::Configuration)?;
+        let config = if shutdown { config | 0x0100 } else { config & !0x0100 };
+        self.write_word(Register::Configuration, config)
+    }
+
+    fn read_word(&mut self, register: Register) -> Result<u16, E> {
+        let mut buffer = [0u8; 2];
+        self.i2c.write_read(self.address, &[register as u8], &mut buffer)?;
+        Ok(u16::from_be_bytes(buffer))
+    }
+
+    fn write_word(&mut self, register: Register, word: u16) -> Result<(), E> {
+        let bytes = word.to_be_bytes();
+        self.i2c.write(self.address, &[register as u8, bytes[0], bytes[1]])
+    }
+}
@@ -1,6 +1,7 @@
-/* Linker script for the STM32F103C8T6 */
+/* Linker script for the STM32F103RBT6 on the Nucleo-F103RB */
 MEMORY
 {
-  FLASH : ORIGIN = 0x08000000, LENGTH = 64K
+  /* last two 1K pages (0x0801F800..) hold the persistent config, see src/config.rs */
+  FLASH : ORIGIN = 0x08000000, LENGTH = 126K
   RAM : ORIGIN = 0x20000000, LENGTH = 20K
-}
\ No newline at end of file
+}
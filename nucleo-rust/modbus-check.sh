@@ -0,0 +1,50 @@
+#!/bin/sh
+# Checks the Modbus RTU slave on USART2 against a real master, mbpoll (https://github.com/epsilonrt/mbpoll).
+# Set the slave address first with the `c` command (field a) and reset, USART2 then only speaks Modbus.
+# The request decoding itself is covered by the host tests: cargo test --lib --target x86_64-unknown-linux-gnu
+#
+# usage: ./modbus-check.sh [device] [slave address] [baud rate]
+set -u
+
+DEVICE=${1:-/dev/ttyACM0}
+ADDRESS=${2:-1}
+BAUD=${3:-115200}
+#0-based addresses as in the register map, one poll, 1 s timeout
+POLL="mbpoll -m rtu -a $ADDRESS -b $BAUD -P none -0 -1 -o 1"
+FAILURES=0
+
+# check <ok|exception> <description> <mbpoll arguments...>
+check() {
+    expected=$1
+    description=$2
+    shift 2
+    if $POLL "$@" "$DEVICE" >/dev/null 2>&1; then result=ok; else result=exception; fi
+    if [ "$result" = "$expected" ]; then
+        echo "pass  $description"
+    else
+        echo "FAIL  $description: expected $expected, got $result"
+        FAILURES=$((FAILURES + 1))
+    fi
+}
+
+check ok "FC 01 read the backlight coil" -t 0 -r 0
+check ok "FC 04 read readings, channel codes and uptime" -t 3 -r 0 -c 18
+check ok "FC 03 read the page and the config fields" -t 4 -r 0 -c 25
+check exception "FC 04 read past the input registers" -t 3 -r 17 -c 2
+check exception "FC 03 read past the holding registers" -t 4 -r 25
+
+#writes put back what was read
+BACKLIGHT=$($POLL -t 0 -r 0 "$DEVICE" 2>/dev/null | sed -n 's/^\[0\]:[[:space:]]*//p')
+check ok "FC 05 write the backlight coil" -t 0 -r 0 "${BACKLIGHT:-1}"
+check exception "FC 0F write coils past the map" -t 0 -r 0 1 1
+check ok "FC 06 pin dashboard page 0" -t 4 -r 0 0
+check ok "FC 06 resume the dashboard" -t 4 -r 0 65535
+check exception "FC 06 pin a page that does not exist" -t 4 -r 0 100
+#registers 2 and 3 are the sample interval (i) and the contrast (k)
+VALUES=$($POLL -t 4 -r 2 -c 2 "$DEVICE" 2>/dev/null | sed -n 's/^\[[23]\]:[[:space:]]*//p')
+# shellcheck disable=SC2086
+check ok "FC 10 write the interval and the contrast" -t 4 -r 2 ${VALUES:-0 0}
+check exception "FC 10 write past the holding registers" -t 4 -r 24 0 0
+
+echo "$FAILURES failed"
+[ "$FAILURES" -eq 0 ]
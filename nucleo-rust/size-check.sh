@@ -0,0 +1,53 @@
+#!/bin/sh
+# Checks that a linked image leaves the config pages and enough stack free, run by CI on every commit.
+# The linker only catches an image overflowing FLASH, RAM statics can grow into the stack unnoticed.
+#
+# usage: ./size-check.sh [elf]
+set -u
+
+ELF=${1:-target/thumbv7m-none-eabi/release/nucleo-rust}
+SIZE=${SIZE:-llvm-size}
+NM=${NM:-llvm-nm}
+#FLASH in memory.x, the last 2K are the config pages
+FLASH=$((126 * 1024))
+RAM=$((20 * 1024))
+#at least this much RAM above the statics for the stack, RTIC runs every task on it
+STACK=$((8 * 1024))
+FAILURES=0
+
+# section <name>: size of one section of the image, 0 if it has none
+section() {
+    $SIZE -A "$ELF" | awk -v name="$1" '$1 == name { size = $2 } END { print size + 0 }'
+}
+
+# symbol <name>: size of the static whose demangled name ends in `name`, nothing if there is none
+symbol() {
+    size=$($NM -S -C "$ELF" | awk -v name="$1" '{ sub(/::h[0-9a-f]+$/, "") } substr($0, length($0) - length(name) + 1) == name { print $2; exit }')
+    [ -n "$size" ] && echo $((0x$size))
+}
+
+# check <description> <used> <budget>
+check() {
+    if [ -n "$2" ] && [ "$2" -le "$3" ]; then
+        echo "pass  $1: $2 of $3 bytes"
+    else
+        echo "FAIL  $1: ${2:-missing} bytes, budget $3"
+        FAILURES=$((FAILURES + 1))
+    fi
+}
+
+$SIZE -A "$ELF" | grep -v '^\.debug\|^\.comment\|^\.ARM\|^Total\|^$' || exit 1
+
+FLASH_USED=$(($(section .vector_table) + $(section .text) + $(section .rodata) + $(section .data)))
+RAM_USED=$(($(section .data) + $(section .bss) + $(section .uninit)))
+check "flash below the config pages" "$FLASH_USED" "$FLASH"
+check "static RAM leaving $STACK bytes of stack" "$RAM_USED" $((RAM - STACK))
+#4 channels, 3 tiers of 60 entries each
+check "history" "$(symbol shared_resource_history)" 4608
+#5 slots of up to MAX_PAYLOAD bytes, one receive buffer each for USART1 and USB
+check "command queue" "$(symbol local_init_command_queue)" 1408
+check "USART1 command receive" "$(symbol local_resource_rx_state)" 288
+check "USB command receive" "$(symbol local_resource_usb_rx_state)" 288
+
+echo "$FAILURES failed"
+[ "$FAILURES" -eq 0 ]
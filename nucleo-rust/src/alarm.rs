@@ -0,0 +1,103 @@
+use crate::config::Threshold;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum AlarmState {
+    Normal,
+    /// out of range, waiting for the debounce count
+    Warning,
+    Alarm,
+    /// alarm silenced by the operator, returns to Normal once the value recovers
+    Acknowledged,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum Event {
+    /// true if the high threshold was crossed
+    Raised { high: bool },
+    Cleared,
+}
+
+#[derive(Copy, Clone)]
+pub struct Alarm {
+    /// channel code the alarm watches
+    pub code: u8,
+    pub state: AlarmState,
+    /// true if the last violation was above the high threshold
+    pub high: bool,
+    /// consecutive out of range samples
+    count: u8,
+}
+
+impl Alarm {
+    pub const fn new(code: u8) -> Alarm {
+        Alarm { code, state: AlarmState::Normal, high: false, count: 0 }
+    }
+
+    /// Feeds one fresh sample, value is in the threshold's unit
+    pub fn update(&mut self, value: i32, threshold: &Threshold) -> Option<Event> {
+        let low = threshold.low as i32;
+        let high = threshold.high as i32;
+        let hysteresis = threshold.hysteresis as i32;
+        let out = value < low || value > high;
+        //back in range only once past the hysteresis band, so a value hovering at a threshold doesn't flap
+        let clear = value >= low + hysteresis && value <= high - hysteresis;
+
+        match self.state {
+            AlarmState::Normal | AlarmState::Warning => {
+                if out {
+                    self.high = value > high;
+                    self.count = self.count.saturating_add(1);
+                    if self.count >= threshold.debounce {
+                        self.state = AlarmState::Alarm;
+                        return Some(Event::Raised { high: self.high });
+                    }
+                    self.state = AlarmState::Warning;
+                } else {
+                    self.state = AlarmState::Normal;
+                    self.count = 0;
+                }
+                None
+            }
+            AlarmState::Alarm | AlarmState::Acknowledged => {
+                if clear {
+                    self.state = AlarmState::Normal;
+                    self.count = 0;
+                    return Some(Event::Cleared);
+                }
+                None
+            }
+        }
+    }
+
+    /// Silences an active alarm, false if there was nothing to acknowledge
+    pub fn acknowledge(&mut self) -> bool {
+        if self.state == AlarmState::Alarm {
+            self.state = AlarmState::Acknowledged;
+            return true;
+        }
+        false
+    }
+}
+
+pub struct Alarms {
+    pub alarms: [Alarm; 3],
+}
+
+impl Alarms {
+    pub const fn new() -> Alarms {
+        Alarms { alarms: [Alarm::new(b't'), Alarm::new(b'h'), Alarm::new(b'g')] }
+    }
+
+    /// true if any alarm needs the display and backlight indication
+    pub fn active(&self) -> bool {
+        self.alarms.iter().any(|a| a.state == AlarmState::Alarm)
+    }
+
+    pub fn acknowledge_all(&mut self) -> bool {
+        let mut acknowledged = false;
+        for alarm in self.alarms.iter_mut() {
+            acknowledged |= alarm.acknowledge();
+        }
+        acknowledged
+    }
+}
@@ -0,0 +1,61 @@
+use embedded_hal::digital::v2::InputPin;
+
+/// Polls the button is stable for before a change counts, at the 100 Hz poll rate
+const DEBOUNCE: u8 = 3;
+/// Polls held down before a press counts as long
+const LONG_PRESS: u16 = 100;
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum Press {
+    Short,
+    /// reported while still held, the release that follows is ignored
+    Long,
+}
+
+/// Debounced push-button on an active low input, such as the Nucleo user button on PC13
+pub struct Button<P> {
+    pin: P,
+    pressed: bool,
+    /// consecutive polls disagreeing with `pressed`
+    count: u8,
+    /// polls since the debounced press
+    held: u16,
+}
+
+impl<P: InputPin> Button<P> {
+    pub fn new(pin: P) -> Button<P> {
+        Button { pin, pressed: false, count: 0, held: 0 }
+    }
+
+    /// Call at a fixed rate, returns a press once it is known to be short or long
+    pub fn poll(&mut self) -> Option<Press> {
+        let down = self.pin.is_low().unwrap_or(false);
+        if down != self.pressed {
+            self.count += 1;
+            if self.count < DEBOUNCE {
+                return self.hold();
+            }
+            self.count = 0;
+            self.pressed = down;
+            if down {
+                self.held = 0;
+            } else if self.held < LONG_PRESS {
+                return Some(Press::Short);
+            }
+            return None;
+        }
+        self.count = 0;
+        self.hold()
+    }
+
+    fn hold(&mut self) -> Option<Press> {
+        if !self.pressed || self.held > LONG_PRESS {
+            return None;
+        }
+        self.held += 1;
+        if self.held == LONG_PRESS {
+            return Some(Press::Long);
+        }
+        None
+    }
+}
@@ -0,0 +1,225 @@
+/// Q16.16 fixed point, the Cortex-M3 has no FPU
+const ONE: i64 = 1 << 16;
+/// ln 2 in Q16.16
+const LN2: i64 = 45426;
+/// Magnus coefficients over water: a in Q16.16 (17.62), b in centi-degrees (243.12 °C)
+const MAGNUS_A: i64 = 1154744;
+const MAGNUS_B: i64 = 24312;
+/// Saturation vapour pressure coefficients (Bolton): a in Q16.16 (17.67), b in centi-degrees (243.5 °C)
+const VAPOUR_A: i64 = 1158021;
+const VAPOUR_B: i64 = 24350;
+/// 6.112 hPa * 2.1674 (water vapour gas constant), x10000
+const VAPOUR_DENSITY: i64 = 132472;
+/// Rothfusz regression coefficients x1e8, in °F and %RH
+const ROTHFUSZ: [i64; 9] = [-4237900000, 204901523, 1014333127, -22475541, -683783, -5481717, 122874, 85282, -199];
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum Comfort {
+    Cold,
+    Dry,
+    Comfortable,
+    Warm,
+    Humid,
+    Hot,
+}
+
+impl Comfort {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Comfort::Cold => "cold",
+            Comfort::Dry => "dry",
+            Comfort::Comfortable => "comfortable",
+            Comfort::Warm => "warm",
+            Comfort::Humid => "humid",
+            Comfort::Hot => "hot",
+        }
+    }
+}
+
+/// Metrics derived from a temperature and relative humidity pair
+#[derive(Copy, Clone)]
+pub struct Climate {
+    /// centi-degrees Celsius
+    pub dew_point: i16,
+    /// apparent temperature in centi-degrees Celsius, the air temperature itself below about 27 °C
+    pub heat_index: i16,
+    /// water vapour density, centi-grams per cubic metre
+    pub absolute_humidity: u16,
+    pub comfort: Comfort,
+}
+
+impl Climate {
+    /// `temperature` in centi-degrees Celsius, `humidity` in centi-percent; None for 0 % humidity
+    pub fn new(temperature: i16, humidity: u16) -> Option<Climate> {
+        let humidity = humidity.min(10000);
+        let dew_point = dew_point(temperature, humidity)?;
+        let heat_index = heat_index(temperature, humidity);
+        let comfort = if heat_index >= 3200 {
+            Comfort::Hot
+        } else if temperature < 1800 {
+            Comfort::Cold
+        } else if dew_point > 1600 || humidity > 6500 {
+            Comfort::Humid
+        } else if humidity < 3000 {
+            Comfort::Dry
+        } else if temperature > 2600 {
+            Comfort::Warm
+        } else {
+            Comfort::Comfortable
+        };
+        Some(Climate { dew_point, heat_index, absolute_humidity: absolute_humidity(temperature, humidity), comfort })
+    }
+}
+
+/// Magnus formula, centi-degrees
+fn dew_point(temperature: i16, humidity: u16) -> Option<i16> {
+    if humidity == 0 {
+        return None;
+    }
+    let t = temperature as i64;
+    let gamma = ln(humidity as i64 * ONE / 10000) + MAGNUS_A * t / (MAGNUS_B + t);
+    Some((MAGNUS_B * gamma / (MAGNUS_A - gamma)) as i16)
+}
+
+/// NOAA heat index in centi-degrees Celsius: Steadman's simple formula, the Rothfusz regression
+/// once that reaches 80 °F; NOAA's corrections for extreme humidity are left out
+fn heat_index(temperature: i16, humidity: u16) -> i16 {
+    //centi-°F and centi-%
+    let t = crate::fixed::fahrenheit(temperature as i32) as i64;
+    let r = humidity as i64;
+    let mut index = (t + 6100 + (t - 6800) * 6 / 5 + r * 94 / 1000) / 2;
+    if (index + t) / 2 >= 8000 {
+        let c = ROTHFUSZ;
+        let (t2, r2) = (t * t, r * r);
+        let sum = c[0] + c[1] * t / 100 + c[2] * r / 100 + c[3] * t * r / 10_000 + c[4] * t2 / 10_000 + c[5] * r2 / 10_000
+            + c[6] * t2 / 100 * r / 10_000 + c[7] * t * (r2 / 100) / 10_000 + c[8] * (t2 / 100) * (r2 / 100) / 10_000;
+        index = sum / 1_000_000;
+    }
+    ((index - 3200) * 5 / 9) as i16
+}
+
+/// Water vapour density in centi-grams per cubic metre
+fn absolute_humidity(temperature: i16, humidity: u16) -> u16 {
+    let t = temperature as i64;
+    let saturation = exp(VAPOUR_A * t / (VAPOUR_B + t));
+    (VAPOUR_DENSITY * saturation * humidity as i64 / (100 * ONE * (27315 + t))) as u16
+}
+
+/// Natural logarithm of a positive Q16.16 number
+fn ln(x: i64) -> i64 {
+    //x = m * 2^k with m in [1, 2)
+    let (mut m, mut k) = (x, 0);
+    while m >= 2 * ONE {
+        m >>= 1;
+        k += 1;
+    }
+    while m < ONE {
+        m <<= 1;
+        k -= 1;
+    }
+    //ln m = 2 atanh(y) with y = (m - 1) / (m + 1) below 1/3, five terms of the series
+    let y = (m - ONE) * ONE / (m + ONE);
+    let y2 = y * y / ONE;
+    let (mut term, mut sum) = (y, 0);
+    for n in [1, 3, 5, 7, 9] {
+        sum += term / n;
+        term = term * y2 / ONE;
+    }
+    k * LN2 + 2 * sum
+}
+
+/// e^x of a Q16.16 number
+fn exp(x: i64) -> i64 {
+    //x = k ln 2 + r with r in [0, ln 2), e^r from eight terms of the series
+    let (k, r) = (x.div_euclid(LN2), x.rem_euclid(LN2));
+    let (mut term, mut sum) = (ONE, ONE);
+    for n in 1..8 {
+        term = term * r / (ONE * n);
+        sum += term;
+    }
+    if k >= 0 { sum << k } else { sum >> -k }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `actual` within `tolerance` of `expected`
+    fn near(actual: i64, expected: i64, tolerance: i64) -> bool {
+        (actual - expected).abs() <= tolerance
+    }
+
+    #[test]
+    fn logarithm_and_exponential() {
+        assert_eq!(ln(ONE), 0);
+        assert!(near(ln(2 * ONE), LN2, 2));
+        assert!(near(ln(ONE / 2), -LN2, 2));
+        //ln 10 = 2.302585
+        assert!(near(ln(10 * ONE), 150902, 8));
+        //ln 0.01 = -4.605170
+        assert!(near(ln(ONE / 100), -301804, 64));
+        assert_eq!(exp(0), ONE);
+        //e and 1/e
+        assert!(near(exp(ONE), 178145, 8));
+        assert!(near(exp(-ONE), 24109, 4));
+        for x in [-ONE, -ONE / 3, ONE / 7, 2 * ONE] {
+            assert!(near(ln(exp(x)), x, 8), "ln(exp({}))", x);
+        }
+        //e^-3 keeps about 12 bits
+        assert!(near(ln(exp(-3 * ONE)), -3 * ONE, 32));
+    }
+
+    #[test]
+    fn dew_point() {
+        let dew_point = |t, h| Climate::new(t, h).unwrap().dew_point as i64;
+        //Magnus: 13.85 °C
+        assert!(near(dew_point(2500, 5000), 1385, 5));
+        //saturated air is at its dew point
+        assert!(near(dew_point(2500, 10000), 2500, 5));
+        assert!(near(dew_point(-1000, 10000), -1000, 5));
+        //-12.80 °C over water
+        assert!(near(dew_point(-1000, 8000), -1280, 5));
+        //-3.67 °C
+        assert!(near(dew_point(2000, 2000), -367, 5));
+        //above 100 % is clamped, no humidity has no dew point
+        assert_eq!(Climate::new(2500, 12000).unwrap().dew_point, Climate::new(2500, 10000).unwrap().dew_point);
+        assert!(Climate::new(2500, 0).is_none());
+    }
+
+    #[test]
+    fn heat_index() {
+        //references from the same formulas in floating point
+        let heat_index = |t, h| super::heat_index(t, h) as i64;
+        //Rothfusz: 32 °C at 70 % feels like 40.41 °C
+        assert!(near(heat_index(3200, 7000), 4041, 10));
+        //40.56 °C (105 °F) at 40 %: 49.72 °C
+        assert!(near(heat_index(4056, 4000), 4972, 10));
+        //Steadman below 80 °F stays near the air temperature
+        assert!(near(heat_index(2000, 5000), 1936, 5));
+        assert!(near(heat_index(2500, 0), 2356, 5));
+        assert!(near(heat_index(-1000, 10000), -1233, 5));
+    }
+
+    #[test]
+    fn absolute_humidity() {
+        let absolute = |t, h| Climate::new(t, h).unwrap().absolute_humidity as i64;
+        //saturation vapour density 23.0 g/m³ at 25 °C
+        assert!(near(absolute(2500, 10000), 2303, 10));
+        assert!(near(absolute(2500, 5000), 1152, 5));
+        //1.89 g/m³ at -10 °C and 80 %
+        assert!(near(absolute(-1000, 8000), 189, 2));
+        assert!(near(absolute(0, 10000), 485, 3));
+        assert_eq!(super::absolute_humidity(2500, 0), 0);
+    }
+
+    #[test]
+    fn comfort() {
+        let comfort = |t, h| Climate::new(t, h).unwrap().comfort;
+        assert!(comfort(2200, 4500) == Comfort::Comfortable);
+        assert!(comfort(3200, 7000) == Comfort::Hot);
+        assert!(comfort(-500, 8000) == Comfort::Cold);
+        assert!(comfort(2200, 2000) == Comfort::Dry);
+        assert!(comfort(2200, 8000) == Comfort::Humid);
+        assert!(comfort(2700, 4000) == Comfort::Warm);
+    }
+}
@@ -0,0 +1,138 @@
+use core::fmt;
+
+/// RTC counter values below 2020-01-01 mean the clock was never set, a fresh backup domain counts from 0
+const VALID_SINCE: u32 = 1_577_836_800;
+
+/// Wall clock time, the RTC counter holds it as Unix seconds (UTC)
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// None if the clock has not been set yet
+    pub fn from_timestamp(timestamp: u32) -> Option<DateTime> {
+        if timestamp < VALID_SINCE {
+            return None;
+        }
+        let days = timestamp / 86400;
+        let seconds = timestamp % 86400;
+        let (year, month, day) = civil_from_days(days);
+        Some(DateTime {
+            year,
+            month,
+            day,
+            hour: (seconds / 3600) as u8,
+            minute: (seconds / 60 % 60) as u8,
+            second: (seconds % 60) as u8,
+        })
+    }
+
+    pub fn timestamp(&self) -> u32 {
+        days_from_civil(self.year, self.month, self.day) * 86400
+            + self.hour as u32 * 3600 + self.minute as u32 * 60 + self.second as u32
+    }
+
+    /// Rejects dates the RTC can't hold and out of range fields
+    pub fn is_valid(&self) -> bool {
+        let days_in_month = match self.month {
+            2 if self.year.is_multiple_of(4) && (!self.year.is_multiple_of(100) || self.year.is_multiple_of(400)) => 29,
+            2 => 28,
+            4 | 6 | 9 | 11 => 30,
+            1..=12 => 31,
+            _ => return false,
+        };
+        (2020..2106).contains(&self.year) && self.day >= 1 && self.day <= days_in_month
+            && self.hour < 24 && self.minute < 60 && self.second < 60
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04}-{:02}-{:02} {:02}:{:02}:{:02}", self.year, self.month, self.day, self.hour, self.minute, self.second)
+    }
+}
+
+/// days since 1970-01-01, valid from 1970 on
+fn days_from_civil(year: u16, month: u8, day: u8) -> u32 {
+    //years start in March so the leap day is the last day of the year
+    let y = if month <= 2 { year as u32 - 1 } else { year as u32 };
+    let era = y / 400;
+    let yoe = y - era * 400;
+    let m = month as u32;
+    let doy = (153 * (if m > 2 { m - 3 } else { m + 9 }) + 2) / 5 + day as u32 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+fn civil_from_days(days: u32) -> (u16, u8, u8) {
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u8;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u8;
+    let year = (yoe + era * 400 + if month <= 2 { 1 } else { 0 }) as u16;
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn calendar_dates() {
+        let dates = [
+            ((1970, 1, 1), 0),
+            ((1970, 3, 1), 59),
+            ((2000, 2, 29), 11016),
+            ((2000, 3, 1), 11017),
+            ((2020, 1, 1), 18262),
+            ((2100, 2, 28), 47540),
+            //2100 is no leap year
+            ((2100, 3, 1), 47541),
+            //the RTC counter ends in 2106
+            ((2106, 2, 7), 49710),
+        ];
+        for ((year, month, day), days) in dates {
+            assert_eq!(days_from_civil(year, month, day), days, "{}-{}-{}", year, month, day);
+            assert_eq!(civil_from_days(days), (year, month, day));
+        }
+    }
+
+    #[test]
+    fn every_day_round_trips() {
+        let mut previous = civil_from_days(0);
+        for days in 1..=u32::MAX / 86400 {
+            let (year, month, day) = civil_from_days(days);
+            assert_eq!(days_from_civil(year, month, day), days);
+            //consecutive days, a new month starts at 1
+            assert!(day == previous.2 + 1 || (day == 1 && (month, year) != (previous.1, previous.0)));
+            previous = (year, month, day);
+        }
+    }
+
+    #[test]
+    fn timestamps() {
+        assert!(DateTime::from_timestamp(0).is_none());
+        assert!(DateTime::from_timestamp(VALID_SINCE - 1).is_none());
+        let leap = DateTime { year: 2024, month: 2, day: 29, hour: 12, minute: 34, second: 56 };
+        assert!(leap.is_valid());
+        assert_eq!(DateTime::from_timestamp(leap.timestamp()), Some(leap));
+        //the largest year is_valid takes, up to its last second
+        let last = DateTime { year: 2105, month: 12, day: 31, hour: 23, minute: 59, second: 59 };
+        assert!(last.is_valid());
+        assert_eq!(DateTime::from_timestamp(last.timestamp()), Some(last));
+        assert_eq!(format!("{}", DateTime::from_timestamp(u32::MAX).unwrap()), "2106-02-07 06:28:15");
+        assert!(!DateTime { year: 2106, ..last }.is_valid());
+        assert!(!DateTime { year: 2100, month: 2, day: 29, ..last }.is_valid());
+        assert!(!DateTime { year: 2019, ..last }.is_valid());
+    }
+}
@@ -1,43 +1,201 @@
-use heapless::Vec;
+use core::str;
+use heapless::{Vec, spsc::{Queue, Producer, Consumer}};
+
+/// heapless queues keep one slot free, so this holds 4 pending commands
+const QUEUE_SIZE: usize = 5;
+/// Most payload bytes after the command code; every queued or partly received command takes this much RAM
+pub const MAX_PAYLOAD: usize = 256;
+/// Length byte announcing a big-endian u16 length, for commands longer than the 9 bytes a digit can give
+const EXTENDED_LENGTH: u8 = b'+';
+
+pub type CommandQueue = Queue<Command, QUEUE_SIZE>;
+pub type CommandProducer = Producer<'static, Command, QUEUE_SIZE>;
+pub type CommandConsumer = Consumer<'static, Command, QUEUE_SIZE>;
+
+/// Serial link a command came in on, its replies go back the same way
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Port {
+    Uart,
+    Usb,
+}
 
 pub struct Command {
     pub cmd: CommandCodes,
-    pub args: Vec<u8, 4>,
-    pub len: usize
+    pub args: Vec<u8, MAX_PAYLOAD>,
+    /// announced length, command code included
+    pub len: usize,
+    pub port: Port,
+    /// longer than MAX_PAYLOAD allows, the payload was discarded and the command is only reported
+    pub oversize: bool
 }
 
+/// Lives in a task's local resource for good, the large Data variant is never copied around
+#[allow(clippy::large_enum_variant)]
 pub enum RxState {
     Length,
+    /// `+` came in, `high` is the first length byte once received
+    ExtendedLength { high: Option<u8> },
     Data { command: Command, idx: usize },
 }
 
 impl Command {
-    pub fn new(length: usize) -> Command {
+    pub fn new(length: usize, port: Port) -> Command {
         Command {
             len: length,
             cmd: CommandCodes::NoCommand,
-            args: Vec::new()
+            args: Vec::new(),
+            port,
+            oversize: length > MAX_PAYLOAD + 1
         }
     }
 
-    pub fn copy(&self, to: &mut Command) {
-        to.len = self.len;
-        to.cmd = self.cmd;
-        to.args = Vec::new();
-        for i in 0..self.args.len() {
-            to.args.push(self.args[i]).unwrap();
+    /// Typed decoding of the payload from byte `start` on
+    pub fn reader(&self, start: usize) -> Args<'_> {
+        Args { bytes: self.args.get(start..).unwrap_or(&[]) }
+    }
+}
+
+/// Reads arguments off the front of a payload, multi-byte values are big-endian;
+/// None once the payload is too short for the value
+pub struct Args<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Args<'a> {
+    fn take<const N: usize>(&mut self) -> Option<[u8; N]> {
+        let bytes = self.bytes.get(..N)?.try_into().ok()?;
+        self.bytes = &self.bytes[N..];
+        Some(bytes)
+    }
+
+    pub fn u8(&mut self) -> Option<u8> {
+        self.take::<1>().map(|b| b[0])
+    }
+
+    pub fn u16(&mut self) -> Option<u16> {
+        self.take().map(u16::from_be_bytes)
+    }
+
+    pub fn i16(&mut self) -> Option<i16> {
+        self.take().map(i16::from_be_bytes)
+    }
+
+    /// The remaining bytes
+    pub fn bytes(&mut self) -> &'a [u8] {
+        core::mem::take(&mut self.bytes)
+    }
+
+    /// The remaining bytes as text, None if they aren't UTF-8
+    pub fn str(&mut self) -> Option<&'a str> {
+        str::from_utf8(self.bytes()).ok()
+    }
+}
+
+impl RxState {
+    /// Feeds one received byte of a |len||cmd||args..| frame, returns the command it completes;
+    /// len is an ASCII digit, or `+` and a big-endian u16 for longer commands.
+    /// Oversize commands are received to their end so the stream stays in sync, then returned without payload
+    pub fn push(&mut self, received: u8, port: Port) -> Option<Command> {
+        match self {
+            RxState::Length => {
+                if received.is_ascii_digit() {
+                    let cmd_length = received - b'0';
+                    if cmd_length > 0 {
+                        *self = RxState::Data {
+                            command: Command::new(cmd_length as usize, port),
+                            idx: 0,
+                        };
+                    }
+                } else if received == EXTENDED_LENGTH {
+                    *self = RxState::ExtendedLength { high: None };
+                }
+                None
+            }
+
+            RxState::ExtendedLength { high: None } => {
+                *self = RxState::ExtendedLength { high: Some(received) };
+                None
+            }
+
+            RxState::ExtendedLength { high: Some(high) } => {
+                let cmd_length = u16::from_be_bytes([*high, received]) as usize;
+                *self = if cmd_length > 0 {
+                    RxState::Data { command: Command::new(cmd_length, port), idx: 0 }
+                } else {
+                    RxState::Length
+                };
+                None
+            }
+
+            RxState::Data { command, idx } => {
+                if *idx == 0 {
+                    command.cmd = CommandCodes::from_byte(received);
+                } else if !command.oversize {
+                    //can't fail, oversize commands don't store their payload
+                    let _res = command.args.push(received);
+                }
+                *idx += 1;
+                if *idx < command.len {
+                    return None;
+                }
+                match core::mem::replace(self, RxState::Length) {
+                    RxState::Data { command, .. } => Some(command),
+                    _ => None,
+                }
+            }
         }
     }
 }
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, PartialEq)]
 pub enum CommandCodes {
     NoCommand = 0,
+    Alarm = 97,
+    Dashboard = 98,
+    Config = 99,
+    Clock = 100,
     DisplayGas = 103,
     DisplayHumidity = 104,
+    Info = 105,
     DisplayKris = 107,
     DisplayLightOn = 108,
+    ConfigurePrecise = 109,
+    Stream = 111,
+    Power = 112,
     ReadSensors = 114,
     DisplayLightOff = 115,
-    DisplayTemperature = 116
-}
\ No newline at end of file
+    DisplayTemperature = 116,
+    Supply = 117,
+    DisplayWrite = 119,
+    History = 118,
+    CalibrateGas = 122
+}
+
+impl CommandCodes {
+    /// Every command the firmware executes, reported by Info
+    pub const SUPPORTED: [CommandCodes; 19] = [
+        CommandCodes::Alarm,
+        CommandCodes::Dashboard,
+        CommandCodes::Config,
+        CommandCodes::Clock,
+        CommandCodes::DisplayGas,
+        CommandCodes::DisplayHumidity,
+        CommandCodes::Info,
+        CommandCodes::DisplayKris,
+        CommandCodes::DisplayLightOn,
+        CommandCodes::ConfigurePrecise,
+        CommandCodes::Stream,
+        CommandCodes::Power,
+        CommandCodes::ReadSensors,
+        CommandCodes::DisplayLightOff,
+        CommandCodes::DisplayTemperature,
+        CommandCodes::Supply,
+        CommandCodes::History,
+        CommandCodes::DisplayWrite,
+        CommandCodes::CalibrateGas,
+    ];
+
+    pub fn from_byte(code: u8) -> CommandCodes {
+        CommandCodes::SUPPORTED.iter().copied().find(|c| *c as u8 == code).unwrap_or(CommandCodes::NoCommand)
+    }
+}
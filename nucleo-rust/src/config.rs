@@ -0,0 +1,383 @@
+use stm32f1xx_hal::flash::{self, FlashWriter};
+
+use crate::crc::crc16;
+use crate::fixed::{MAX_DECIMALS, Style};
+use crate::sensor::analog::{Calibration, MAX_OVERSAMPLE};
+
+/// Config pages at the end of the 128K flash, excluded from FLASH in memory.x
+pub const CONFIG_OFFSET: u32 = 126 * 1024;
+pub const PAGE_SIZE: u32 = 1024;
+const PAGES: u32 = 2;
+
+/// Records are appended to the active page and the other page is erased only
+/// when the active one is full, so each config write costs no erase until then
+const RECORD_SIZE: usize = 128;
+const RECORDS_PER_PAGE: u32 = PAGE_SIZE / RECORD_SIZE as u32;
+const RECORD_MAGIC: u16 = 0x4346;
+const RECORD_VERSION: u8 = 1;
+const HEADER_SIZE: usize = 8;
+/// The fields take the first 54 bytes, the rest is reserved and written as zeroes,
+/// so a field added there reads 0 from older records
+const PAYLOAD_SIZE: usize = RECORD_SIZE - HEADER_SIZE - 2;
+
+#[derive(Copy, Clone)]
+pub struct Threshold {
+    /// in the channel's stored unit (centi-degrees, centi-percent, ppm)
+    pub low: i16,
+    pub high: i16,
+    /// distance back inside the range needed to clear an alarm
+    pub hysteresis: i16,
+    /// consecutive out of range samples needed to raise an alarm
+    pub debounce: u8,
+}
+
+impl Threshold {
+    /// The hysteresis band has to leave room to clear in, or a raised alarm would never clear
+    fn is_valid(&self) -> bool {
+        self.low < self.high && self.hysteresis >= 0 && self.debounce > 0
+            && self.hysteresis as i32 * 2 < self.high as i32 - self.low as i32
+    }
+}
+
+#[derive(Copy, Clone)]
+pub struct Config {
+    pub baud_rate: u32,
+    /// seconds between sensor samples
+    pub sample_interval: u16,
+    /// PCD8544 voltage coefficient, 0..90
+    pub contrast: u8,
+    pub backlight: bool,
+    pub debug: bool,
+    pub temperature_alarm: Threshold,
+    pub humidity_alarm: Threshold,
+    pub gas_alarm: Threshold,
+    /// MQ-7 clean air baseline in ohms, 0 until calibrated
+    pub mq7_r0: u32,
+    /// added to DHT11 temperatures, centi-degrees
+    pub temperature_offset: i16,
+    /// STOP mode between samples and display switched off when idle
+    pub low_power: bool,
+    /// seconds between dashboard pages, 0 keeps the current page
+    pub dashboard_interval: u16,
+    /// bit n enables dashboard::PAGE_LIST[n] in the rotation
+    pub dashboard_pages: u16,
+    /// Modbus RTU slave address on USART2, 0 for the native command protocol; applied after reset
+    pub modbus_address: u8,
+    /// DHT22/AM2302 on the DHT pin instead of a DHT11
+    pub dht22: bool,
+    /// temperatures shown in Fahrenheit, alarm thresholds stay in Celsius
+    pub fahrenheit: bool,
+    /// decimals of temperature and humidity readings, 0..=MAX_DECIMALS
+    pub decimals: u8,
+    /// analog scans averaged per reading, 1..=MAX_OVERSAMPLE
+    pub adc_oversample: u8,
+    /// linear calibration of the spare analog inputs A1 and A2
+    pub analog_calibration: [Calibration; 2],
+}
+
+impl Config {
+    pub const fn new() -> Config {
+        Config {
+            baud_rate: 115200,
+            sample_interval: 1,
+            contrast: 56,
+            backlight: true,
+            debug: false,
+            temperature_alarm: Threshold { low: 500, high: 3500, hysteresis: 50, debounce: 3 },
+            humidity_alarm: Threshold { low: 2000, high: 8000, hysteresis: 200, debounce: 3 },
+            gas_alarm: Threshold { low: -1, high: 50, hysteresis: 5, debounce: 2 },
+            mq7_r0: 0,
+            temperature_offset: 0,
+            low_power: false,
+            dashboard_interval: 5,
+            dashboard_pages: 0xFFFF,
+            modbus_address: 0,
+            dht22: false,
+            fahrenheit: false,
+            decimals: 1,
+            adc_oversample: 8,
+            analog_calibration: [Calibration::NONE; 2],
+        }
+    }
+
+    /// Rejects values the hardware cannot take, so a bad record falls back to defaults
+    pub fn is_valid(&self) -> bool {
+        self.baud_rate >= 1200 && self.baud_rate <= 460800 && self.sample_interval > 0 && self.contrast < 91
+            && self.temperature_alarm.is_valid() && self.humidity_alarm.is_valid() && self.gas_alarm.is_valid()
+            && self.dashboard_pages != 0 && self.modbus_address <= 247
+            && self.decimals <= MAX_DECIMALS && (1..=MAX_OVERSAMPLE).contains(&self.adc_oversample)
+    }
+
+    /// Formatting of readings, `ascii` for the display
+    pub fn style(&self, ascii: bool) -> Style {
+        Style { decimals: self.decimals, fahrenheit: self.fahrenheit, ascii }
+    }
+
+    /// alarm threshold of a channel code
+    pub fn threshold_mut(&mut self, code: u8) -> Option<&mut Threshold> {
+        match code {
+            b't' => Some(&mut self.temperature_alarm),
+            b'h' => Some(&mut self.humidity_alarm),
+            b'g' => Some(&mut self.gas_alarm),
+            _ => None,
+        }
+    }
+
+    pub fn threshold(&self, code: u8) -> Option<Threshold> {
+        match code {
+            b't' => Some(self.temperature_alarm),
+            b'h' => Some(self.humidity_alarm),
+            b'g' => Some(self.gas_alarm),
+            _ => None,
+        }
+    }
+
+    /// Sets one field from a command argument, fields are selected by letter:
+    /// b baud/100, i sample interval, k contrast, l backlight, v debug, o temperature offset, p low-power profile,
+    /// r dashboard interval, m dashboard page mask, t/T h/H g/G low/high alarm thresholds, a Modbus address, d DHT22,
+    /// f Fahrenheit, n decimals, q analog oversampling, x/X y/Y gain (x1000) / offset of analog inputs A1 and A2;
+    /// false for an unknown field or invalid result
+    pub fn set(&mut self, field: u8, value: u16) -> bool {
+        let mut config = *self;
+        match field {
+            b'b' => config.baud_rate = value as u32 * 100,
+            b'i' => config.sample_interval = value,
+            b'k' => config.contrast = value as u8,
+            b'l' => config.backlight = value != 0,
+            b'v' => config.debug = value != 0,
+            b'o' => config.temperature_offset = value as i16,
+            b'p' => config.low_power = value != 0,
+            b'r' => config.dashboard_interval = value,
+            b'm' => config.dashboard_pages = value,
+            b't' => config.temperature_alarm.low = value as i16,
+            b'T' => config.temperature_alarm.high = value as i16,
+            b'h' => config.humidity_alarm.low = value as i16,
+            b'H' => config.humidity_alarm.high = value as i16,
+            b'g' => config.gas_alarm.low = value as i16,
+            b'G' => config.gas_alarm.high = value as i16,
+            b'a' => config.modbus_address = value as u8,
+            b'd' => config.dht22 = value != 0,
+            b'f' => config.fahrenheit = value != 0,
+            b'n' => config.decimals = value.min(u8::MAX as u16) as u8,
+            b'q' => config.adc_oversample = value.min(u8::MAX as u16) as u8,
+            b'x' => config.analog_calibration[0].gain = value,
+            b'X' => config.analog_calibration[0].offset = value as i16,
+            b'y' => config.analog_calibration[1].gain = value,
+            b'Y' => config.analog_calibration[1].offset = value as i16,
+            _ => return false,
+        }
+        if !config.is_valid() {
+            return false;
+        }
+        *self = config;
+        true
+    }
+
+    /// Reads one field by the letters `set` takes, None for an unknown field
+    pub fn get(&self, field: u8) -> Option<u16> {
+        let value = match field {
+            b'b' => (self.baud_rate / 100) as u16,
+            b'i' => self.sample_interval,
+            b'k' => self.contrast as u16,
+            b'l' => self.backlight as u16,
+            b'v' => self.debug as u16,
+            b'o' => self.temperature_offset as u16,
+            b'p' => self.low_power as u16,
+            b'r' => self.dashboard_interval,
+            b'm' => self.dashboard_pages,
+            b't' => self.temperature_alarm.low as u16,
+            b'T' => self.temperature_alarm.high as u16,
+            b'h' => self.humidity_alarm.low as u16,
+            b'H' => self.humidity_alarm.high as u16,
+            b'g' => self.gas_alarm.low as u16,
+            b'G' => self.gas_alarm.high as u16,
+            b'a' => self.modbus_address as u16,
+            b'd' => self.dht22 as u16,
+            b'f' => self.fahrenheit as u16,
+            b'n' => self.decimals as u16,
+            b'q' => self.adc_oversample as u16,
+            b'x' => self.analog_calibration[0].gain,
+            b'X' => self.analog_calibration[0].offset as u16,
+            b'y' => self.analog_calibration[1].gain,
+            b'Y' => self.analog_calibration[1].offset as u16,
+            _ => return None,
+        };
+        Some(value)
+    }
+
+    fn encode(&self, payload: &mut [u8; PAYLOAD_SIZE]) {
+        let mut w = Cursor { buffer: payload, pos: 0 };
+        w.put(&self.baud_rate.to_le_bytes());
+        w.put(&self.sample_interval.to_le_bytes());
+        w.put(&[self.contrast, self.backlight as u8, self.debug as u8]);
+        for threshold in [self.temperature_alarm, self.humidity_alarm, self.gas_alarm] {
+            w.put(&threshold.low.to_le_bytes());
+            w.put(&threshold.high.to_le_bytes());
+            w.put(&threshold.hysteresis.to_le_bytes());
+            w.put(&[threshold.debounce]);
+        }
+        w.put(&self.mq7_r0.to_le_bytes());
+        w.put(&self.temperature_offset.to_le_bytes());
+        w.put(&[self.low_power as u8]);
+        w.put(&self.dashboard_interval.to_le_bytes());
+        w.put(&self.dashboard_pages.to_le_bytes());
+        w.put(&[self.modbus_address, self.dht22 as u8, self.fahrenheit as u8, self.decimals, self.adc_oversample]);
+        for calibration in self.analog_calibration {
+            w.put(&calibration.gain.to_le_bytes());
+            w.put(&calibration.offset.to_le_bytes());
+        }
+    }
+
+    fn decode(payload: &[u8]) -> Config {
+        let mut r = Reader { buffer: payload, pos: 0 };
+        let baud_rate = r.u32();
+        let sample_interval = r.u16();
+        let contrast = r.u8();
+        let backlight = r.u8() != 0;
+        let debug = r.u8() != 0;
+        let mut threshold = || Threshold { low: r.u16() as i16, high: r.u16() as i16, hysteresis: r.u16() as i16, debounce: r.u8() };
+        let (temperature_alarm, humidity_alarm, gas_alarm) = (threshold(), threshold(), threshold());
+        let mq7_r0 = r.u32();
+        let temperature_offset = r.u16() as i16;
+        let low_power = r.u8() != 0;
+        let dashboard_interval = r.u16();
+        let dashboard_pages = r.u16();
+        let modbus_address = r.u8();
+        let dht22 = r.u8() != 0;
+        let fahrenheit = r.u8() != 0;
+        let decimals = r.u8();
+        let adc_oversample = r.u8();
+        let mut calibration = || Calibration { gain: r.u16(), offset: r.u16() as i16 };
+        let analog_calibration = [calibration(), calibration()];
+        Config {
+            baud_rate,
+            sample_interval,
+            contrast,
+            backlight,
+            debug,
+            temperature_alarm,
+            humidity_alarm,
+            gas_alarm,
+            mq7_r0,
+            temperature_offset,
+            low_power,
+            dashboard_interval,
+            dashboard_pages,
+            modbus_address,
+            dht22,
+            fahrenheit,
+            decimals,
+            adc_oversample,
+            analog_calibration,
+        }
+    }
+}
+
+struct Cursor<'a> {
+    buffer: &'a mut [u8],
+    pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn put(&mut self, bytes: &[u8]) {
+        self.buffer[self.pos..self.pos + bytes.len()].copy_from_slice(bytes);
+        self.pos += bytes.len();
+    }
+}
+
+struct Reader<'a> {
+    buffer: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take<const N: usize>(&mut self) -> [u8; N] {
+        let mut bytes = [0u8; N];
+        bytes.copy_from_slice(&self.buffer[self.pos..self.pos + N]);
+        self.pos += N;
+        bytes
+    }
+
+    fn u8(&mut self) -> u8 {
+        self.take::<1>()[0]
+    }
+
+    fn u16(&mut self) -> u16 {
+        u16::from_le_bytes(self.take())
+    }
+
+    fn u32(&mut self) -> u32 {
+        u32::from_le_bytes(self.take())
+    }
+}
+
+/// Wear-levelled config storage in the last two flash pages
+pub struct ConfigStore {
+    sequence: u32,
+    /// slot index of the latest record, counted across both pages
+    slot: Option<u32>,
+}
+
+impl ConfigStore {
+    pub const fn new() -> ConfigStore {
+        ConfigStore { sequence: 0, slot: None }
+    }
+
+    /// Returns the newest record with a valid CRC, None if there is none
+    pub fn load(&mut self, writer: &FlashWriter) -> Option<Config> {
+        let mut latest: Option<(u32, Config)> = None;
+        for slot in 0..PAGES * RECORDS_PER_PAGE {
+            let record = match writer.read(slot_offset(slot), RECORD_SIZE) {
+                Ok(record) => record,
+                Err(_) => continue,
+            };
+            let magic = u16::from_le_bytes([record[0], record[1]]);
+            let crc = u16::from_le_bytes([record[RECORD_SIZE - 2], record[RECORD_SIZE - 1]]);
+            let version = record[6];
+            if magic != RECORD_MAGIC || version != RECORD_VERSION
+                || crc16(&record[..RECORD_SIZE - 2]) != crc {
+                continue;
+            }
+            let sequence = u32::from_le_bytes([record[2], record[3], record[4], record[5]]);
+            let config = Config::decode(&record[HEADER_SIZE..HEADER_SIZE + PAYLOAD_SIZE]);
+            if config.is_valid() && latest.is_none_or(|(seq, _)| sequence > seq) {
+                latest = Some((sequence, config));
+                self.sequence = sequence;
+                self.slot = Some(slot);
+            }
+        }
+        latest.map(|(_, config)| config)
+    }
+
+    /// Appends a record after the latest one, erasing a page when moving onto it
+    pub fn save(&mut self, writer: &mut FlashWriter, config: &Config) -> Result<(), flash::Error> {
+        let slot = match self.slot {
+            Some(slot) => (slot + 1) % (PAGES * RECORDS_PER_PAGE),
+            None => 0,
+        };
+        if slot % RECORDS_PER_PAGE == 0 {
+            writer.page_erase(slot_offset(slot))?;
+        }
+
+        let sequence = self.sequence.wrapping_add(1);
+        let mut record = [0xFFu8; RECORD_SIZE];
+        record[0..2].copy_from_slice(&RECORD_MAGIC.to_le_bytes());
+        record[2..6].copy_from_slice(&sequence.to_le_bytes());
+        record[6] = RECORD_VERSION;
+        record[7] = 0;
+        let mut payload = [0u8; PAYLOAD_SIZE];
+        config.encode(&mut payload);
+        record[HEADER_SIZE..HEADER_SIZE + PAYLOAD_SIZE].copy_from_slice(&payload);
+        let crc = crc16(&record[..RECORD_SIZE - 2]);
+        record[RECORD_SIZE - 2..].copy_from_slice(&crc.to_le_bytes());
+
+        writer.write(slot_offset(slot), &record)?;
+        self.sequence = sequence;
+        self.slot = Some(slot);
+        Ok(())
+    }
+}
+
+fn slot_offset(slot: u32) -> u32 {
+    CONFIG_OFFSET + slot * RECORD_SIZE as u32
+}
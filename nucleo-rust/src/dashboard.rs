@@ -0,0 +1,312 @@
+use core::fmt::Write;
+use heapless::String;
+use lcd_hal::{Display, font, pcd8544::Pcd8544};
+
+use crate::{Lcd, View};
+use crate::alarm::AlarmState;
+use crate::clock::DateTime;
+use crate::sensor::Value;
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum Page {
+    /// every channel on one screen
+    Summary,
+    /// one channel in double size digits
+    Big(u8),
+    /// last samples of a channel as a graph
+    Trend(u8),
+    Alarms,
+    Uptime,
+    /// dew point, heat index, absolute humidity and comfort
+    Climate,
+    /// supply voltage and MCU temperature
+    Supply,
+}
+
+pub const PAGE_LIST: [Page; 10] = [
+    Page::Summary,
+    Page::Big(b't'),
+    Page::Big(b'h'),
+    Page::Big(b'g'),
+    Page::Big(b'p'),
+    Page::Trend(b't'),
+    Page::Alarms,
+    Page::Uptime,
+    Page::Climate,
+    Page::Supply,
+];
+
+/// Rotation state of the dashboard pages
+pub struct Dashboard {
+    /// index into PAGE_LIST
+    pub page: usize,
+    pub paused: bool,
+    /// stays on `page` until resumed
+    pub pinned: bool,
+    /// uptime of the last page change
+    changed: u32,
+    /// another screen is shown, sample refreshes leave it alone until the next page change
+    held: bool,
+    /// host content from DisplayWrite is shown, no page or clock is drawn over it until released
+    pub host: bool,
+}
+
+impl Dashboard {
+    pub const fn new() -> Dashboard {
+        Dashboard { page: 0, paused: false, pinned: false, changed: 0, held: false, host: false }
+    }
+
+    /// Page to draw this tick: the next enabled page once `interval` elapsed,
+    /// otherwise the current page again when new samples came in
+    pub fn update(&mut self, now: u32, interval: u16, pages: u16, sampled: bool) -> Option<Page> {
+        if self.host {
+            return None;
+        }
+        let rotating = !self.paused && !self.pinned && interval > 0;
+        if rotating && now.wrapping_sub(self.changed) >= interval as u32 {
+            return Some(self.next(now, pages));
+        }
+        if sampled && !self.held {
+            return Some(self.current());
+        }
+        None
+    }
+
+    pub fn next(&mut self, now: u32, pages: u16) -> Page {
+        for step in 1..=PAGE_LIST.len() {
+            let page = (self.page + step) % PAGE_LIST.len();
+            if pages & (1 << page) != 0 {
+                self.page = page;
+                break;
+            }
+        }
+        self.changed = now;
+        self.held = false;
+        self.host = false;
+        PAGE_LIST[self.page]
+    }
+
+    pub fn current(&self) -> Page {
+        PAGE_LIST[self.page]
+    }
+
+    /// Shows `page` until resume, None for an unknown page index
+    pub fn pin(&mut self, page: usize) -> Option<Page> {
+        let pinned = *PAGE_LIST.get(page)?;
+        self.page = page;
+        self.pinned = true;
+        self.held = false;
+        self.host = false;
+        Some(pinned)
+    }
+
+    pub fn resume(&mut self, now: u32) {
+        self.paused = false;
+        self.pinned = false;
+        self.changed = now;
+        self.host = false;
+    }
+
+    /// A command or alarm screen took over the display, it stays up for one interval
+    pub fn hold(&mut self, now: u32) {
+        self.changed = now;
+        self.held = true;
+    }
+
+    /// The host drew on the display, it stays up until a dashboard command or `release`
+    pub fn show_host(&mut self) {
+        self.held = true;
+        self.host = true;
+    }
+
+    /// Back from host content to the pages, rotating as before it
+    pub fn release(&mut self, now: u32) {
+        self.changed = now;
+        self.held = false;
+        self.host = false;
+    }
+}
+
+/// Frame buffer in the PCD8544 vertical addressing order: 6 bytes per column, one bit per pixel
+struct Frame {
+    buffer: [u8; 6 * 84],
+}
+
+impl Frame {
+    fn new() -> Frame {
+        Frame { buffer: [0; 6 * 84] }
+    }
+
+    fn set_pixel(&mut self, x: usize, y: usize) {
+        if x < 84 && y < 48 {
+            self.buffer[x * 6 + y / 8] |= 1 << (y % 8);
+        }
+    }
+
+    /// Draws text with its top left corner at (x, y), every font pixel `scale` pixels wide
+    fn text(&mut self, x: usize, y: usize, text: &[u8], scale: usize) {
+        for (i, &c) in text.iter().enumerate() {
+            let glyph = match font::ASCII.get((c as usize).wrapping_sub(0x20)) {
+                Some(glyph) => glyph,
+                None => continue,
+            };
+            for (column, bits) in glyph.iter().enumerate() {
+                for row in 0..8 {
+                    if bits & (1 << row) == 0 {
+                        continue;
+                    }
+                    for dx in 0..scale {
+                        for dy in 0..scale {
+                            self.set_pixel(x + (i * 6 + column) * scale + dx, y + row * scale + dy);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Vertical line between y0 and y1 inclusive
+    fn vline(&mut self, x: usize, y0: usize, y1: usize) {
+        for y in y0.min(y1)..=y0.max(y1) {
+            self.set_pixel(x, y);
+        }
+    }
+}
+
+/// Draws a dashboard page, the clock corner is added by the caller
+pub fn draw(display: &mut Lcd, page: Page, view: &View) {
+    let View { sensors, history, alarms, style, uptime, time, .. } = *view;
+    match page {
+        Page::Summary => {
+            display.clear().unwrap();
+            //raw ADC counts only matter for calibration, row 5 is left to the clock
+            let channels = sensors.iter().filter(|c| !matches!(c.reading.map(|r| r.value), Some(Value::Raw(_))));
+            for (row, channel) in (0u8..5).zip(channels) {
+                let mut text: String<14> = String::new();
+                let name = &channel.name[..channel.name.len().min(6)];
+                let _res = match (channel.reading, channel.error) {
+                    (Some(reading), None) => write!(text, "{:<6} {}", name, reading.value.styled(style)),
+                    _ => write!(text, "{:<6} --", name),
+                };
+                display.set_position(0u8, row).unwrap();
+                display.print(text.as_bytes()).unwrap();
+            }
+        }
+        Page::Big(code) => {
+            let channel = match sensors.get(code) {
+                Some(channel) => channel,
+                None => return,
+            };
+            let mut frame = Frame::new();
+            frame.text(0, 0, channel.name.as_bytes(), 1);
+            let mut text: String<14> = String::new();
+            let _res = match channel.reading {
+                Some(reading) => write!(text, "{}", reading.value.styled(style)),
+                None => write!(text, "--"),
+            };
+            frame.text(0, 14, text.as_bytes(), 2);
+            if channel.error.is_some() {
+                frame.text(0, 32, b"stale", 1);
+            }
+            display.draw_buffer(&frame.buffer).unwrap();
+        }
+        Page::Trend(code) => {
+            let (channel, channel_history) = match (sensors.get(code), history.get(code)) {
+                (Some(channel), Some(channel_history)) => (channel, channel_history),
+                _ => return,
+            };
+            let mut frame = Frame::new();
+            frame.text(0, 0, channel.name.as_bytes(), 1);
+            let tier = &channel_history.tiers[0];
+            if let (Some(stats), Some(reading)) = (tier.stats(), channel.reading) {
+                //graph in the right 60 columns between the title and the clock row, min and max labels on the left
+                let (min, max) = (stats.min as i32, stats.max as i32);
+                let span = (max - min).max(1);
+                let mut label: String<14> = String::new();
+                let _res = write!(label, "{}", reading.value.like(max).styled(style));
+                frame.text(0, 9, &label.as_bytes()[..label.len().min(4)], 1);
+                label.clear();
+                let _res = write!(label, "{}", reading.value.like(min).styled(style));
+                frame.text(0, 32, &label.as_bytes()[..label.len().min(4)], 1);
+                let mut previous = None;
+                for (i, entry) in tier.iter().enumerate() {
+                    let y = 39 - ((entry.mean as i32 - min) * 30 / span) as usize;
+                    let x = 24 + i;
+                    frame.vline(x, y, previous.unwrap_or(y));
+                    previous = Some(y);
+                }
+            }
+            display.draw_buffer(&frame.buffer).unwrap();
+        }
+        Page::Alarms => {
+            display.clear().unwrap();
+            display.print(b"Alarms").unwrap();
+            for (row, alarm) in alarms.alarms.iter().enumerate() {
+                if let Some(channel) = sensors.get(alarm.code) {
+                    let state = match alarm.state {
+                        AlarmState::Normal => "ok",
+                        AlarmState::Warning => "warn",
+                        AlarmState::Alarm => "ALARM",
+                        AlarmState::Acknowledged => "ack",
+                    };
+                    let mut text: String<14> = String::new();
+                    let _res = write!(text, "{:<8} {}", &channel.name[..channel.name.len().min(8)], state);
+                    display.set_position(0u8, row as u8 + 1).unwrap();
+                    display.print(text.as_bytes()).unwrap();
+                }
+            }
+        }
+        Page::Uptime => {
+            display.clear().unwrap();
+            display.print(b"Uptime").unwrap();
+            let mut text: String<14> = String::new();
+            let _res = write!(text, "{}d {:02}:{:02}:{:02}", uptime / 86400, uptime / 3600 % 24, uptime / 60 % 60, uptime % 60);
+            display.set_position(0u8, 1u8).unwrap();
+            display.print(text.as_bytes()).unwrap();
+            if let Some(now) = DateTime::from_timestamp(time) {
+                text.clear();
+                let _res = write!(text, "{:04}-{:02}-{:02}", now.year, now.month, now.day);
+                display.set_position(0u8, 3u8).unwrap();
+                display.print(text.as_bytes()).unwrap();
+            }
+        }
+        Page::Climate => {
+            display.clear().unwrap();
+            display.print(b"Climate").unwrap();
+            let climate = match sensors.climate() {
+                Some(climate) => climate,
+                None => {
+                    display.set_position(0u8, 2u8).unwrap();
+                    display.print(b"--").unwrap();
+                    return;
+                }
+            };
+            let rows = [
+                ("Dew", Value::Temperature(climate.dew_point)),
+                ("Feels", Value::Temperature(climate.heat_index)),
+                ("Water", Value::AbsoluteHumidity(climate.absolute_humidity)),
+            ];
+            for (row, (label, value)) in rows.iter().enumerate() {
+                let mut text: String<14> = String::new();
+                let _res = write!(text, "{:<6}{}", label, value.styled(style));
+                display.set_position(0u8, row as u8 + 1).unwrap();
+                display.print(text.as_bytes()).unwrap();
+            }
+            display.set_position(0u8, 4u8).unwrap();
+            display.print(climate.comfort.name().as_bytes()).unwrap();
+        }
+        Page::Supply => {
+            display.clear().unwrap();
+            display.print(b"Supply").unwrap();
+            for (row, (label, code)) in [("VDDA", b'v'), ("MCU", b'm')].iter().enumerate() {
+                let mut text: String<14> = String::new();
+                let _res = match sensors.get(*code).and_then(|c| c.reading) {
+                    Some(reading) => write!(text, "{:<5}{}", label, reading.value.styled(style)),
+                    None => write!(text, "{:<5}--", label),
+                };
+                display.set_position(0u8, row as u8 + 1).unwrap();
+                display.print(text.as_bytes()).unwrap();
+            }
+        }
+    }
+}
@@ -0,0 +1,125 @@
+//the fault record lives in RAM that startup leaves alone, which needs raw access
+#![allow(unsafe_code)]
+
+use core::{fmt::{self, Write}, mem::MaybeUninit, panic::PanicInfo, ptr};
+use cortex_m::peripheral::SCB;
+use cortex_m_rt::{exception, ExceptionFrame};
+use stm32f1xx_hal::pac::RCC;
+
+const RECORD_MAGIC: u32 = 0x4641_554C;
+const MESSAGE_SIZE: usize = 96;
+
+#[derive(Copy, Clone, Debug)]
+pub enum ResetCause {
+    PowerOn,
+    Pin,
+    Software,
+    Watchdog,
+    WindowWatchdog,
+    LowPower,
+}
+
+impl ResetCause {
+    /// Reads the RCC_CSR reset flags and clears them for the next reset;
+    /// the F1 has no separate brown-out flag, a brown-out shows up as PowerOn
+    pub fn read(rcc: &RCC) -> ResetCause {
+        let csr = rcc.csr.read();
+        let cause = if csr.lpwrrstf().bit_is_set() {
+            ResetCause::LowPower
+        } else if csr.wwdgrstf().bit_is_set() {
+            ResetCause::WindowWatchdog
+        } else if csr.iwdgrstf().bit_is_set() {
+            ResetCause::Watchdog
+        } else if csr.sftrstf().bit_is_set() {
+            ResetCause::Software
+        } else if csr.porrstf().bit_is_set() {
+            ResetCause::PowerOn
+        } else {
+            //NRST is pulled by every internal reset, so the pin flag only counts when nothing else is set
+            ResetCause::Pin
+        };
+        rcc.csr.modify(|_, w| w.rmvf().set_bit());
+        cause
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub enum FaultKind {
+    Panic,
+    HardFault,
+}
+
+/// Last panic or hard fault, kept over the reset that follows it
+#[derive(Copy, Clone)]
+pub struct FaultRecord {
+    magic: u32,
+    kind: u32,
+    /// faulting instruction for a hard fault, 0 for a panic
+    pub pc: u32,
+    len: u32,
+    message: [u8; MESSAGE_SIZE],
+}
+
+impl FaultRecord {
+    pub fn kind(&self) -> FaultKind {
+        if self.kind == FaultKind::HardFault as u32 { FaultKind::HardFault } else { FaultKind::Panic }
+    }
+
+    /// Panic message, cut at MESSAGE_SIZE bytes
+    pub fn message(&self) -> &str {
+        let bytes = &self.message[..(self.len as usize).min(MESSAGE_SIZE)];
+        match core::str::from_utf8(bytes) {
+            Ok(message) => message,
+            Err(e) => core::str::from_utf8(&bytes[..e.valid_up_to()]).unwrap_or(""),
+        }
+    }
+}
+
+impl Write for FaultRecord {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &byte in s.as_bytes() {
+            if self.len as usize >= MESSAGE_SIZE {
+                break;
+            }
+            self.message[self.len as usize] = byte;
+            self.len += 1;
+        }
+        Ok(())
+    }
+}
+
+#[link_section = ".uninit.FAULT_RECORD"]
+static mut FAULT_RECORD: MaybeUninit<FaultRecord> = MaybeUninit::uninit();
+
+/// Returns the record left by a fault before the last reset, at most once
+pub fn take() -> Option<FaultRecord> {
+    unsafe {
+        let record = ptr::addr_of_mut!(FAULT_RECORD).cast::<FaultRecord>();
+        if ptr::read_volatile(ptr::addr_of!((*record).magic)) != RECORD_MAGIC {
+            return None;
+        }
+        ptr::write_volatile(ptr::addr_of_mut!((*record).magic), 0);
+        Some(ptr::read_volatile(record))
+    }
+}
+
+fn record_and_reset(record: FaultRecord) -> ! {
+    unsafe {
+        ptr::write_volatile(ptr::addr_of_mut!(FAULT_RECORD).cast::<FaultRecord>(), record);
+    }
+    SCB::sys_reset()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    cortex_m::interrupt::disable();
+    let mut record = FaultRecord { magic: RECORD_MAGIC, kind: FaultKind::Panic as u32, pc: 0, len: 0, message: [0; MESSAGE_SIZE] };
+    let _ = write!(record, "{}", info);
+    record_and_reset(record)
+}
+
+#[exception]
+unsafe fn HardFault(frame: &ExceptionFrame) -> ! {
+    let record = FaultRecord { magic: RECORD_MAGIC, kind: FaultKind::HardFault as u32, pc: frame.pc(), len: 0, message: [0; MESSAGE_SIZE] };
+    record_and_reset(record)
+}
@@ -0,0 +1,209 @@
+use core::fmt;
+
+/// Most decimals a reading can be shown with, values are stored in hundredths
+pub const MAX_DECIMALS: u8 = 2;
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum Unit {
+    Celsius,
+    Fahrenheit,
+    /// relative humidity
+    Percent,
+    Ppm,
+    /// absolute humidity
+    GramsPerCubicMetre,
+    Volts,
+    /// ADC counts, no suffix
+    Raw,
+}
+
+impl Unit {
+    /// The PCD8544 font has no degree sign or superscripts, `ascii` writes them as 'o' and '3'
+    pub fn suffix(&self, ascii: bool) -> &'static str {
+        match (*self, ascii) {
+            (Unit::Celsius, false) => "°C",
+            (Unit::Celsius, true) => "oC",
+            (Unit::Fahrenheit, false) => "°F",
+            (Unit::Fahrenheit, true) => "oF",
+            (Unit::Percent, _) => "%RH",
+            (Unit::Ppm, _) => "ppm",
+            (Unit::GramsPerCubicMetre, false) => "g/m³",
+            (Unit::GramsPerCubicMetre, true) => "g/m3",
+            (Unit::Volts, _) => "V",
+            (Unit::Raw, _) => "",
+        }
+    }
+
+    /// ppm and counts are whole numbers whatever the style asks for
+    fn is_integer(&self) -> bool {
+        matches!(self, Unit::Ppm | Unit::Raw)
+    }
+}
+
+/// How readings are written on one output
+#[derive(Copy, Clone, PartialEq)]
+pub struct Style {
+    /// 0..=MAX_DECIMALS, more are cut to MAX_DECIMALS
+    pub decimals: u8,
+    /// temperatures converted to Fahrenheit
+    pub fahrenheit: bool,
+    /// suffixes limited to ASCII for the display
+    pub ascii: bool,
+}
+
+impl Style {
+    /// One decimal in Celsius, safe for the display
+    pub const DEFAULT: Style = Style { decimals: 1, fahrenheit: false, ascii: true };
+}
+
+/// Hundredths as a decimal number without unit, rounded half away from zero, e.g. -1.05 or -1.1.
+/// A value rounding to zero loses its sign
+#[derive(Copy, Clone)]
+pub struct Fixed {
+    pub hundredths: i32,
+    pub decimals: u8,
+}
+
+impl Fixed {
+    pub const fn new(hundredths: i32, decimals: u8) -> Fixed {
+        Fixed { hundredths, decimals }
+    }
+}
+
+impl fmt::Display for Fixed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let decimals = self.decimals.min(MAX_DECIMALS) as u32;
+        let step = 10u32.pow(MAX_DECIMALS as u32 - decimals);
+        let rounded = (self.hundredths.unsigned_abs() + step / 2) / step;
+        let sign = if self.hundredths < 0 && rounded != 0 { "-" } else { "" };
+        if decimals == 0 {
+            return write!(f, "{}{}", sign, rounded);
+        }
+        let scale = 10u32.pow(decimals);
+        write!(f, "{}{}.{:0width$}", sign, rounded / scale, rounded % scale, width = decimals as usize)
+    }
+}
+
+/// Centi-degrees Celsius to centi-degrees Fahrenheit, rounded to the nearest hundredth
+pub fn fahrenheit(celsius: i32) -> i32 {
+    let scaled = celsius * 9;
+    let rounding = if scaled < 0 { -2 } else { 2 };
+    (scaled + rounding) / 5 + 3200
+}
+
+/// A value in hundredths of `unit` with its suffix, written as `style` asks
+#[derive(Copy, Clone)]
+pub struct Quantity {
+    pub hundredths: i32,
+    pub unit: Unit,
+    pub style: Style,
+}
+
+impl Quantity {
+    /// `celsius` hundredths in the temperature unit of `style`
+    pub fn temperature(celsius: i32, style: Style) -> Quantity {
+        if style.fahrenheit {
+            Quantity { hundredths: fahrenheit(celsius), unit: Unit::Fahrenheit, style }
+        } else {
+            Quantity { hundredths: celsius, unit: Unit::Celsius, style }
+        }
+    }
+
+    /// Difference of `celsius` hundredths, e.g. an offset, converted without the 32 °F zero shift
+    pub fn temperature_difference(celsius: i32, style: Style) -> Quantity {
+        if style.fahrenheit {
+            Quantity { hundredths: fahrenheit(celsius) - 3200, unit: Unit::Fahrenheit, style }
+        } else {
+            Quantity { hundredths: celsius, unit: Unit::Celsius, style }
+        }
+    }
+}
+
+impl fmt::Display for Quantity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let decimals = if self.unit.is_integer() { 0 } else { self.style.decimals };
+        write!(f, "{}{}", Fixed::new(self.hundredths, decimals), self.unit.suffix(self.style.ascii))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CELSIUS: Style = Style { decimals: 1, fahrenheit: false, ascii: false };
+    const FAHRENHEIT: Style = Style { decimals: 1, fahrenheit: true, ascii: false };
+
+    fn fixed(hundredths: i32, decimals: u8) -> String {
+        format!("{}", Fixed::new(hundredths, decimals))
+    }
+
+    #[test]
+    fn negative_near_zero() {
+        assert_eq!(fixed(-1, 2), "-0.01");
+        assert_eq!(fixed(-4, 1), "0.0");
+        assert_eq!(fixed(-5, 1), "-0.1");
+        assert_eq!(fixed(-49, 0), "0");
+        assert_eq!(fixed(-50, 0), "-1");
+        assert_eq!(fixed(-105, 2), "-1.05");
+        assert_eq!(fixed(-105, 1), "-1.1");
+        assert_eq!(fixed(0, 2), "0.00");
+    }
+
+    #[test]
+    fn rounding_per_decimals() {
+        assert_eq!(fixed(2549, 0), "25");
+        assert_eq!(fixed(2550, 0), "26");
+        assert_eq!(fixed(2544, 1), "25.4");
+        assert_eq!(fixed(2545, 1), "25.5");
+        assert_eq!(fixed(2544, 2), "25.44");
+        assert_eq!(fixed(1999, 1), "20.0");
+        assert_eq!(fixed(7, 2), "0.07");
+        //more than MAX_DECIMALS are cut to it
+        assert_eq!(fixed(1234, 5), "12.34");
+    }
+
+    #[test]
+    fn hundred_and_above() {
+        assert_eq!(fixed(10000, 1), "100.0");
+        assert_eq!(fixed(10000, 0), "100");
+        assert_eq!(fixed(-12345, 2), "-123.45");
+        assert_eq!(fixed(99999, 0), "1000");
+        assert_eq!(fixed(99995, 1), "1000.0");
+    }
+
+    #[test]
+    fn celsius_to_fahrenheit() {
+        assert_eq!(fahrenheit(0), 3200);
+        assert_eq!(fahrenheit(10000), 21200);
+        assert_eq!(fahrenheit(-4000), -4000);
+        assert_eq!(fahrenheit(3700), 9860);
+        assert_eq!(fahrenheit(-1), 3198);
+        assert_eq!(fahrenheit(1), 3202);
+        assert_eq!(format!("{}", Quantity::temperature(2500, FAHRENHEIT)), "77.0°F");
+        assert_eq!(format!("{}", Quantity::temperature(-1778, FAHRENHEIT)), "0.0°F");
+        assert_eq!(format!("{}", Quantity::temperature(2500, CELSIUS)), "25.0°C");
+        //differences have no zero shift
+        assert_eq!(format!("{}", Quantity::temperature_difference(100, FAHRENHEIT)), "1.8°F");
+        assert_eq!(format!("{}", Quantity::temperature_difference(-50, CELSIUS)), "-0.5°C");
+    }
+
+    #[test]
+    fn unit_suffixes() {
+        let quantity = |hundredths, unit, style| format!("{}", Quantity { hundredths, unit, style });
+        let lcd = Style::DEFAULT;
+        assert_eq!(quantity(-530, Unit::Celsius, lcd), "-5.3oC");
+        assert_eq!(quantity(-530, Unit::Fahrenheit, lcd), "-5.3oF");
+        assert_eq!(quantity(4550, Unit::Percent, CELSIUS), "45.5%RH");
+        assert_eq!(quantity(1150, Unit::GramsPerCubicMetre, CELSIUS), "11.5g/m³");
+        assert_eq!(quantity(1150, Unit::GramsPerCubicMetre, lcd), "11.5g/m3");
+        assert_eq!(quantity(329, Unit::Volts, Style { decimals: 2, ..CELSIUS }), "3.29V");
+        //whole numbers whatever the decimals
+        assert_eq!(quantity(4500, Unit::Ppm, Style { decimals: 2, ..CELSIUS }), "45ppm");
+        assert_eq!(quantity(409500, Unit::Raw, CELSIUS), "4095");
+        for ascii in [false, true] {
+            for unit in [Unit::Celsius, Unit::Fahrenheit, Unit::Percent, Unit::Ppm, Unit::GramsPerCubicMetre, Unit::Volts, Unit::Raw] {
+                assert_eq!(unit.suffix(ascii).is_ascii(), ascii || !matches!(unit, Unit::Celsius | Unit::Fahrenheit | Unit::GramsPerCubicMetre));
+            }
+        }
+    }
+}
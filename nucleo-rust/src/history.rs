@@ -0,0 +1,180 @@
+use heapless::HistoryBuffer;
+
+/// Entries kept by each decimation tier
+pub const TIER_CAPACITY: usize = 60;
+/// Default decimation: raw samples -> 60 sample averages -> 60 average aggregates,
+/// i.e. 1 s / 1 min / 1 h at the default 1 s sample interval
+pub const DEFAULT_DECIMATION: [u8; 2] = [60, 60];
+pub const TIERS: usize = 3;
+
+/// One history point, raw samples have min == max == mean
+#[derive(Copy, Clone)]
+pub struct Entry {
+    pub mean: i16,
+    pub min: i16,
+    pub max: i16,
+}
+
+#[derive(Copy, Clone)]
+struct Accumulator {
+    sum: i32,
+    min: i16,
+    max: i16,
+    count: u8,
+}
+
+impl Accumulator {
+    const fn new() -> Accumulator {
+        Accumulator { sum: 0, min: i16::MAX, max: i16::MIN, count: 0 }
+    }
+
+    fn add(&mut self, entry: &Entry) {
+        self.sum += entry.mean as i32;
+        self.min = self.min.min(entry.min);
+        self.max = self.max.max(entry.max);
+        self.count += 1;
+    }
+
+    fn take(&mut self) -> Entry {
+        let entry = Entry { mean: (self.sum / self.count as i32) as i16, min: self.min, max: self.max };
+        *self = Accumulator::new();
+        entry
+    }
+}
+
+pub struct Tier {
+    entries: HistoryBuffer<Entry, TIER_CAPACITY>,
+    /// timestamp of the newest entry
+    pub last: u32,
+}
+
+impl Tier {
+    const fn new() -> Tier {
+        Tier { entries: HistoryBuffer::new(), last: 0 }
+    }
+
+    /// oldest first
+    pub fn iter(&self) -> impl Iterator<Item = &Entry> {
+        self.entries.oldest_ordered()
+    }
+
+    /// None while the tier is empty
+    pub fn stats(&self) -> Option<Stats> {
+        let count = self.entries.len();
+        if count == 0 {
+            return None;
+        }
+        let mut sum = 0i64;
+        let mut squares = 0i64;
+        let mut min = i16::MAX;
+        let mut max = i16::MIN;
+        for entry in self.iter() {
+            sum += entry.mean as i64;
+            squares += entry.mean as i64 * entry.mean as i64;
+            min = min.min(entry.min);
+            max = max.max(entry.max);
+        }
+        let n = count as i64;
+        //population variance of the entry means
+        let variance = (squares * n - sum * sum) / (n * n);
+        Some(Stats {
+            count: count as u16,
+            min,
+            max,
+            mean: (sum / n) as i16,
+            stddev: isqrt(variance.max(0) as u64) as i16,
+        })
+    }
+}
+
+#[derive(Copy, Clone)]
+pub struct Stats {
+    pub count: u16,
+    pub min: i16,
+    pub max: i16,
+    pub mean: i16,
+    pub stddev: i16,
+}
+
+/// Sample history of one channel, raw samples decimated into two averaging tiers
+pub struct ChannelHistory {
+    pub code: u8,
+    pub tiers: [Tier; TIERS],
+    accumulators: [Accumulator; TIERS - 1],
+}
+
+impl ChannelHistory {
+    pub const fn new(code: u8) -> ChannelHistory {
+        ChannelHistory {
+            code,
+            tiers: [Tier::new(), Tier::new(), Tier::new()],
+            accumulators: [Accumulator::new(), Accumulator::new()],
+        }
+    }
+
+    /// Adds a raw sample, `decimation[i]` entries of tier i make one entry of tier i + 1
+    pub fn push(&mut self, value: i16, now: u32, decimation: &[u8; TIERS - 1]) {
+        let mut entry = Entry { mean: value, min: value, max: value };
+        for (tier, history) in self.tiers.iter_mut().enumerate() {
+            history.entries.write(entry);
+            history.last = now;
+            if tier == TIERS - 1 {
+                break;
+            }
+            let accumulator = &mut self.accumulators[tier];
+            accumulator.add(&entry);
+            if accumulator.count < decimation[tier] {
+                break;
+            }
+            entry = accumulator.take();
+        }
+    }
+
+    pub fn clear(&mut self) {
+        *self = ChannelHistory::new(self.code);
+    }
+}
+
+pub struct History {
+    pub channels: [ChannelHistory; 4],
+    pub decimation: [u8; TIERS - 1],
+}
+
+impl History {
+    pub const fn new() -> History {
+        History {
+            channels: [
+                ChannelHistory::new(b't'),
+                ChannelHistory::new(b'h'),
+                ChannelHistory::new(b'g'),
+                ChannelHistory::new(b'p'),
+            ],
+            decimation: DEFAULT_DECIMATION,
+        }
+    }
+
+    pub fn get(&self, code: u8) -> Option<&ChannelHistory> {
+        self.channels.iter().find(|c| c.code == code)
+    }
+
+    /// Changes the decimation factors, existing history no longer matches them and is dropped
+    pub fn set_decimation(&mut self, decimation: [u8; TIERS - 1]) {
+        self.decimation = decimation;
+        for channel in self.channels.iter_mut() {
+            channel.clear();
+        }
+    }
+}
+
+fn isqrt(n: u64) -> u64 {
+    if n < 2 {
+        return n;
+    }
+    let mut x = n;
+    let mut y = x.div_ceil(2);
+    while y < x {
+        x = y;
+        y = (x + n / x) / 2;
+    }
+    x
+}
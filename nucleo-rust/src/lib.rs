@@ -0,0 +1,10 @@
+//! Hardware independent parts of the firmware, also built for the host so their tests run there:
+//! `cargo test --lib --target x86_64-unknown-linux-gnu` (the default target is the board's)
+#![deny(unsafe_code)]
+#![cfg_attr(not(test), no_std)]
+
+pub mod climate;
+pub mod clock;
+pub mod crc;
+pub mod fixed;
+pub mod modbus;
@@ -1,326 +1,1573 @@
-//#![deny(unsafe_code)]
+#![deny(unsafe_code)]
 #![no_std]
 #![no_main]
 
+mod alarm;
+mod button;
 mod command;
+mod config;
+mod dashboard;
+mod fault;
+mod history;
+mod menu;
+mod power;
+mod sensor;
+mod stream;
+mod uart;
+mod usb;
 
-use panic_halt as _;
-use cortex_m_rt::entry;
 use stm32f1xx_hal::{
-    adc,
-    pac::{self, interrupt, USART2, SPI2},
-    prelude::*,
-    gpio::{Pin, Output, Alternate},
-    spi::{self, Spi, Spi2NoRemap},
-    serial::{Config, Serial, StopBits, Tx, Rx}};
+    pac::{I2C1, SPI2},
+    gpio::{Pin, Output, OpenDrain, Alternate},
+    i2c::BlockingI2c,
+    spi::{Spi, Spi2NoRemap},
+    timer::SysDelay};
 use core::fmt::Write;
-use heapless::{Vec, String};
-use command::{Command, RxState, CommandCodes};
-use lcd_hal::{Display, pcd8544::spi::Pcd8544Spi};
-use dht11::{Dht11, Measurement};
-
-static mut RX: Option<Rx<USART2>> = None;
-static mut TX: Option<Tx<USART2>> = None;
-static mut CURRENT_COMMAND: Command = Command { len: 1, cmd: CommandCodes::NoCommand, args: Vec::new() };
-static mut RX_STATE: RxState = RxState::Length;
-static mut DISPLAY: Option<Pcd8544Spi<Spi<SPI2, Spi2NoRemap, (Pin<'B', 13, Alternate>, Pin<'B', 14>, Pin<'B', 15, Alternate>), u8>, Pin<'C', 7, Output>, Pin<'B', 10, Output>>> = None;
-static mut LIGHT: Option<Pin<'A', 10, Output>> = None;
-static mut DHT11_MEASUREMENT: Option<Measurement> = None;
-static mut MQ7_MEASUREMENT: u16 = 0u16;
-static DEBUG_MODE: bool = false;
-
-unsafe fn uart_command_response() {
-    if let Some(tx) = TX.as_mut() {
-        writeln!(tx, "Length of cmd is {}\r", CURRENT_COMMAND.len).unwrap();
-        writeln!(tx, "Command code is {}\r", (CURRENT_COMMAND.cmd as u8)).unwrap();
-        for i in 0..CURRENT_COMMAND.args.len() {
-            writeln!(tx, "Argument {} is {}\r", i, CURRENT_COMMAND.args[i]).unwrap();
-        }
+use heapless::String;
+use command::Command;
+use lcd_hal::{Display, pcd8544::{Modes, Pcd8544, spi::Pcd8544Spi}};
+use alarm::{Alarms, Event};
+use history::History;
+use config::Config;
+use sensor::{Registry, Sensor, analog::{self, AnalogPins, AnalogSensor}, dht::{self, DhtSensor}, mq7::{self, Mq7Sensor}, mcp9808::Mcp9808Sensor};
+use uart::SerialOut;
+use power::Power;
+use dashboard::{Dashboard, Page};
+use menu::Menu;
+use stream::Stream;
+use nucleo_rust::{climate, clock::{self, DateTime}, crc, modbus::{self, Exception, Registers}};
+use nucleo_rust::fixed::{self, Quantity, Style};
+
+/// Build identification reported by the Info command
+const FIRMWARE_NAME: &str = env!("CARGO_PKG_NAME");
+const FIRMWARE_VERSION: &str = env!("CARGO_PKG_VERSION");
+const GIT_HASH: &str = env!("GIT_HASH");
+const DISPLAY_TYPE: &str = "PCD8544";
+/// Supply range the STM32F103 ADC is specified for, mV
+const SUPPLY_MIN_MV: i32 = 2400;
+const SUPPLY_MAX_MV: i32 = 3600;
+/// Full screen bitmap in the PCD8544 vertical addressing order: 6 bytes per column, one bit per pixel
+const BITMAP_SIZE: usize = 6 * 84;
+
+type Lcd = Pcd8544Spi<Spi<SPI2, Spi2NoRemap, (Pin<'B', 13, Alternate>, Pin<'B', 14>, Pin<'B', 15, Alternate>), u8>, Pin<'C', 7, Output>, Pin<'B', 10, Output>>;
+type Light = Pin<'A', 10, Output>;
+type Dht = DhtSensor<Pin<'B', 2, Output<OpenDrain>>>;
+type Mq7 = Mq7Sensor<Pin<'B', 0, Output>>;
+type Mcp9808 = Mcp9808Sensor<BlockingI2c<I2C1, (Pin<'B', 8, Alternate<OpenDrain>>, Pin<'B', 9, Alternate<OpenDrain>>)>>;
+
+/// What the display task should draw
+#[derive(Copy, Clone)]
+pub enum Screen {
+    /// channel name and latest reading
+    Channel(u8),
+    Text(&'static [u8]),
+    /// alarm raised on a channel, true if above the high threshold
+    Alarm { code: u8, high: bool },
+    /// only redraws the clock in the bottom right corner
+    Time,
+    Page(Page),
+    /// the button menu in its current state
+    Menu,
+    /// the bitmap uploaded with DisplayWrite, drawn without the clock corner
+    Bitmap,
+}
+
+/// Everything screens are drawn from besides the display itself
+struct View<'a> {
+    sensors: &'a Registry,
+    history: &'a History,
+    alarms: &'a Alarms,
+    menu: &'a Menu,
+    bitmap: &'a [u8; BITMAP_SIZE],
+    /// readings as the config asks, in ASCII
+    style: Style,
+    backlight: bool,
+    mq7_status: Option<mq7::Status>,
+    uptime: u32,
+    /// RTC counter for the clock corner
+    time: u32,
+    /// host content is on screen, the clock corner stays off it
+    host: bool,
+}
+
+fn uart_command_response(tx: &mut SerialOut, command: &Command) {
+    writeln!(tx, "Length of cmd is {}\r", command.len).unwrap();
+    writeln!(tx, "Command code is {}\r", (command.cmd as u8)).unwrap();
+    for i in 0..command.args.len() {
+        writeln!(tx, "Argument {} is {}\r", i, command.args[i]).unwrap();
     }
 }
 
-unsafe fn execute_command() {
-    match CURRENT_COMMAND.cmd {
-        CommandCodes::DisplayGas => { //g => read gas
-            if let Some(display) = DISPLAY.as_mut() {
-                display.clear().unwrap();
-                let _res = display.print(b"Gas reading: ").unwrap();
-                let _res = display.set_position(0u8, 1u8).unwrap();
-                let gas: String<5> = String::from(MQ7_MEASUREMENT);
-                let _res = display.print(gas.as_bytes()).unwrap();
-            }
-        }
-        CommandCodes::DisplayHumidity => { //h => read humidity
-            if let Some(measurement) = DHT11_MEASUREMENT.as_mut() {
-                let hum_full: String<2> = String::from(measurement.humidity / 10);
-                let hum_frac: String<1> = String::from(measurement.humidity % 10);
-                if let Some(display) = DISPLAY.as_mut() {
+fn render(display: &mut Lcd, screen: Screen, view: &View) {
+    let sensors = view.sensors;
+    match screen {
+        Screen::Channel(code) => {
+            if let Some(channel) = sensors.get(code) {
+                if let Some(reading) = channel.reading {
+                    let mut text: String<14> = String::new();
+                    let _res = write!(text, "{}", reading.value.styled(view.style));
                     display.clear().unwrap();
-                    let _res = display.print(b"Humidity: ").unwrap();
-                    let _res = display.set_position(0u8, 1u8).unwrap();
-                    let _res = display.print(hum_full.as_bytes()).unwrap();
-                    let _res = display.print(b".").unwrap();
-                    let _res = display.print(hum_frac.as_bytes()).unwrap();
-                    let _res = display.print(b"%").unwrap();
+                    display.print(channel.name.as_bytes()).unwrap();
+                    display.print(b":").unwrap();
+                    display.set_position(0u8, 1u8).unwrap();
+                    display.print(text.as_bytes()).unwrap();
                 }
             }
         }
-        CommandCodes::DisplayKris => { //k => changes displayed string
-            if let Some(display) = DISPLAY.as_mut() {
+        Screen::Text(text) => {
+            display.clear().unwrap();
+            display.print(text).unwrap();
+        }
+        Screen::Alarm { code, high } => {
+            if let Some(channel) = sensors.get(code) {
+                let mut text: String<14> = String::new();
+                if let Some(reading) = channel.reading {
+                    let _res = write!(text, "{}", reading.value.styled(view.style));
+                }
                 display.clear().unwrap();
-                let _res = display.print(b"Hello Kris").unwrap();
+                display.print(b"ALARM").unwrap();
+                display.set_position(0u8, 2u8).unwrap();
+                display.print(channel.name.as_bytes()).unwrap();
+                display.set_position(0u8, 3u8).unwrap();
+                display.print(text.as_bytes()).unwrap();
+                display.set_position(0u8, 4u8).unwrap();
+                display.print(if high { b"high" } else { b"low" }).unwrap();
             }
         }
-        CommandCodes::DisplayLightOn => { //l => turn on display's BL
-            if let Some(light) = LIGHT.as_mut() {
-                light.set_high();
+        Screen::Time => {}
+        Screen::Page(page) => dashboard::draw(display, page, view),
+        Screen::Menu => menu::draw(display, view.menu, sensors, view.style, view.backlight, view.mq7_status, view.uptime),
+        Screen::Bitmap => {
+            display.draw_buffer(view.bitmap).unwrap();
+            return;
+        }
+    }
+    if view.host {
+        return;
+    }
+    if let Some(now) = DateTime::from_timestamp(view.time) {
+        let mut text: String<5> = String::new();
+        let _res = write!(text, "{:02}:{:02}", now.hour, now.minute);
+        display.set_position(54u8, 5u8).unwrap();
+        display.print(text.as_bytes()).unwrap();
+    }
+}
+
+/// Channels in the Modbus input registers, the first ones registered; the map predates the larger registry
+const MODBUS_CHANNELS: u16 = 8;
+
+/// Modbus data model over the shared resources, 0-based addresses:
+/// coil 0 backlight; input registers 0..8 channel readings in their stored unit (0x8000 without a valid reading),
+/// 8..16 channel codes, 16/17 uptime high/low word; holding register 0 dashboard page (writing pins it, 0xFFFF resumes),
+/// 1.. config fields in MODBUS_CONFIG_FIELDS order as `c` sets them
+struct ModbusMap<'a> {
+    sensors: &'a Registry,
+    config: &'a mut Config,
+    config_dirty: &'a mut bool,
+    backlight: &'a mut bool,
+    light: &'a mut Light,
+    display: &'a mut Lcd,
+    dashboard: &'a mut Dashboard,
+    uptime: u32,
+    /// page to show once the request is answered
+    page: Option<Page>,
+}
+
+const MODBUS_CONFIG_FIELDS: &[u8] = b"biklvoprmtThHgGadfnqxXyY";
+const NO_READING: u16 = 0x8000;
+
+impl<'a> Registers for ModbusMap<'a> {
+    fn coil(&self, address: u16) -> Result<bool, Exception> {
+        match address {
+            0 => Ok(*self.backlight),
+            _ => Err(Exception::IllegalDataAddress),
+        }
+    }
+
+    fn set_coil(&mut self, address: u16, on: bool) -> Result<(), Exception> {
+        match address {
+            0 => {
+                *self.backlight = on;
+                if on {
+                    self.light.set_high();
+                } else {
+                    self.light.set_low();
+                }
+                Ok(())
             }
+            _ => Err(Exception::IllegalDataAddress),
         }
-        CommandCodes::ReadSensors => { //r => read measurements, [g,h,t]
-            if let Some(tx) = TX.as_mut() {
-                for i in 0..CURRENT_COMMAND.args.len() {
-                    match CURRENT_COMMAND.args[i] {
-                        103 => { //g
-                            writeln!(tx, "Gas sensor reading is {}\r", MQ7_MEASUREMENT).unwrap();
-                        }
-                        104 => { //h
-                            if let Some(measurement) = DHT11_MEASUREMENT.as_mut() {
-                                let hum_full: String<2> = String::from(measurement.humidity / 10);
-                                let hum_frac: String<1> = String::from(measurement.humidity % 10);
-                                writeln!(tx, "Humidity is {}.{}%\r", hum_full, hum_frac).unwrap();
-                            }
-                        }
-                        116 => { //t
-                            if let Some(measurement) = DHT11_MEASUREMENT.as_mut() {
-                                let temp_full: String<2> = String::from(measurement.temperature / 10);
-                                let temp_frac: String<1> = String::from(measurement.temperature % 10);
-                                writeln!(tx, "Temperature is {}.{}oC\r", temp_full, temp_frac).unwrap();
-                            }
-                        }
-                        _ => {}
-                    }
+    }
+
+    fn input_register(&self, address: u16) -> Result<u16, Exception> {
+        let channels = MODBUS_CHANNELS;
+        match address {
+            a if a < channels => Ok(match self.sensors.iter().nth(a as usize) {
+                Some(channel) if channel.is_valid() => channel.reading.map_or(NO_READING, |r| r.value.as_i32() as u16),
+                _ => NO_READING,
+            }),
+            a if a < 2 * channels => Ok(self.sensors.iter().nth((a - channels) as usize).map_or(0, |c| c.code as u16)),
+            16 => Ok((self.uptime >> 16) as u16),
+            17 => Ok(self.uptime as u16),
+            _ => Err(Exception::IllegalDataAddress),
+        }
+    }
+
+    fn holding_register(&self, address: u16) -> Result<u16, Exception> {
+        match address {
+            0 => Ok(self.dashboard.page as u16),
+            a => MODBUS_CONFIG_FIELDS.get(a as usize - 1).and_then(|&field| self.config.get(field)).ok_or(Exception::IllegalDataAddress),
+        }
+    }
+
+    fn set_holding_register(&mut self, address: u16, value: u16) -> Result<(), Exception> {
+        match address {
+            0 if value == 0xFFFF => {
+                if self.dashboard.host {
+                    self.page = Some(self.dashboard.current());
+                }
+                self.dashboard.resume(self.uptime);
+                Ok(())
+            }
+            0 => {
+                self.page = Some(self.dashboard.pin(value as usize).ok_or(Exception::IllegalDataValue)?);
+                Ok(())
+            }
+            a => {
+                let field = *MODBUS_CONFIG_FIELDS.get(a as usize - 1).ok_or(Exception::IllegalDataAddress)?;
+                if !self.config.set(field, value) {
+                    return Err(Exception::IllegalDataValue);
+                }
+                *self.config_dirty = true;
+                if field == b'k' {
+                    self.display.set_lcd_coefficients(self.config.contrast, 0, 4).unwrap();
                 }
+                Ok(())
             }
         }
-        CommandCodes::DisplayLightOff => { //s => turn off display's BL
-            if let Some(light) = LIGHT.as_mut() {
-                light.set_low();
+    }
+}
+
+fn dht_model(config: &Config) -> dht::Model {
+    if config.dht22 { dht::Model::Dht22 } else { dht::Model::Dht11 }
+}
+
+/// Samples every sensor and publishes the results for ReadSensors
+fn sample_sensors(sensors: &mut [&mut dyn Sensor], delay: &mut SysDelay, now: u32, time: u32, registry: &mut Registry, tx: &mut SerialOut) {
+    for sensor in sensors.iter_mut() {
+        let result = sensor.sample(delay, now);
+        if let Err(nb::Error::Other(e)) = result {
+            writeln!(tx, "{} error: {:?}\r\n", sensor.channels()[0].1, e).unwrap();
+        }
+        registry.store(sensor.channels(), result, now, time);
+    }
+}
+
+/// Scans the analog inputs with the configured settings and hands the MQ-7 its counts and VDDA
+fn scan_analog(analog: &mut AnalogSensor, mq7: &mut Mq7, config: &Config) {
+    analog.set_oversample(config.adc_oversample);
+    analog.set_calibration(config.analog_calibration);
+    analog.scan();
+    mq7.set_input(analog.counts(analog::MQ7), analog.vdda());
+}
+
+/// Runs readings sampled at `now` through the alarm state machines and reports transitions,
+/// returns the alarm screen to show for a newly raised alarm
+fn check_alarms(alarms: &mut Alarms, sensors: &Registry, config: &Config, tx: &mut SerialOut, now: u32) -> Option<Screen> {
+    let mut screen = None;
+    let style = config.style(false);
+    for alarm in alarms.alarms.iter_mut() {
+        let (channel, threshold) = match (sensors.get(alarm.code), config.threshold(alarm.code)) {
+            (Some(channel), Some(threshold)) => (channel, threshold),
+            _ => continue,
+        };
+        let reading = match channel.reading {
+            Some(reading) if channel.error.is_none() && reading.timestamp == now => reading,
+            _ => continue,
+        };
+        match alarm.update(reading.value.as_i32(), &threshold) {
+            Some(Event::Raised { high }) => {
+                let side = if high { "high" } else { "low" };
+                writeln!(tx, "ALARM {} {} {}\r", channel.name, side, reading.value.styled(style)).unwrap();
+                screen = Some(Screen::Alarm { code: alarm.code, high });
+            }
+            Some(Event::Cleared) => {
+                writeln!(tx, "Alarm cleared {} {}\r", channel.name, reading.value.styled(style)).unwrap();
             }
+            None => {}
         }
-        CommandCodes::DisplayTemperature => { //t => read temperature
-            if let Some(measurement) = DHT11_MEASUREMENT.as_mut() {
-                let temp_full: String<2> = String::from(measurement.temperature / 10);
-                let temp_frac: String<1> = String::from(measurement.temperature % 10);
-                if let Some(display) = DISPLAY.as_mut() {
-                    display.clear().unwrap();
-                    let _res = display.print(b"Temperature: ").unwrap();
-                    let _res = display.set_position(0u8, 1u8).unwrap();
-                    let _res = display.print(temp_full.as_bytes()).unwrap();
-                    let _res = display.print(b".").unwrap();
-                    let _res = display.print(temp_frac.as_bytes()).unwrap();
-                    let _res = display.print(b"oC").unwrap();
+    }
+    screen
+}
+
+/// Adds readings sampled at `now` to the channel histories
+fn record_history(history: &mut History, sensors: &Registry, now: u32) {
+    let decimation = history.decimation;
+    for channel_history in history.channels.iter_mut() {
+        if let Some(channel) = sensors.get(channel_history.code) {
+            match channel.reading {
+                Some(reading) if channel.error.is_none() && reading.timestamp == now => {
+                    channel_history.push(reading.value.as_i32() as i16, now, &decimation);
                 }
+                _ => {}
             }
         }
-        _ => {}
     }
 }
 
-#[interrupt]
-unsafe fn USART2() {
-    cortex_m::interrupt::free(|_| {
-        if let Some(rx) = RX.as_mut() {
+/// Flashes the backlight and inverts the display while an alarm is active, called every tick;
+/// `indication` tracks whether the alarm indication is currently shown
+fn update_alarm_indication(alarms: &Alarms, light: &mut Light, display: &mut Lcd, backlight: bool, indication: &mut bool) {
+    let active = alarms.active();
+    if active {
+        light.toggle();
+    } else if *indication {
+        if backlight {
+            light.set_high();
+        } else {
+            light.set_low();
+        }
+    }
+    if active != *indication {
+        display.set_mode(if active { Modes::Inverse } else { Modes::Normal }).unwrap();
+        *indication = active;
+    }
+}
+
+/// Records UART or alarm activity and brings back a display switched off for inactivity
+fn wake(power: &mut Power, now: u32, display: &mut Lcd, light: &mut Light, backlight: bool) {
+    power.last_activity = now;
+    power.stop = false;
+    if power.display_off {
+        display.set_power_down(false).unwrap();
+        if backlight {
+            light.set_high();
+        }
+        power.display_off = false;
+    }
+}
+
+#[rtic::app(device = stm32f1xx_hal::pac, peripherals = true, dispatchers = [EXTI0])]
+mod app {
+    use super::*;
+    use stm32f1xx_hal::{
+        adc,
+        flash::{self, FlashSize, SectorSize},
+        pac::{EXTI, TIM2, USART2},
+        gpio::{Edge, ExtiPin, Floating, Input},
+        timer::{CounterHz, Event as TimerEvent},
+        prelude::*,
+        i2c,
+        rtc::Rtc,
+        spi,
+        watchdog::IndependentWatchdog,
+        serial::{self, Serial, StopBits, Rx}};
+    use crate::command::{RxState, CommandCodes, CommandQueue, CommandProducer, CommandConsumer, MAX_PAYLOAD};
+    use crate::alarm::AlarmState;
+    use crate::history::TIERS;
+    use crate::config::ConfigStore;
+    use crate::sensor::{Value, mq7, mcp9808::Settings, analog::{ScanBuffer, SCAN_LENGTH}};
+    use crate::uart::{SerialDrain, TxQueue};
+    use crate::fault::{self, ResetCause};
+    use crate::power;
+    use crate::button::Button;
+    use crate::menu::Action;
+    use crate::stream::Format;
+    use crate::modbus::{self, Receiver, FrameQueue, FrameProducer, FrameConsumer};
+    use crate::usb::{UsbDev, UsbSerial, UsbDrain};
+    use crate::command::Port;
+    use stm32f1xx_hal::pac::Interrupt;
+    use stm32f1xx_hal::usb::{Peripheral, UsbBus, UsbBusType};
+    use usb_device::{bus::UsbBusAllocator, prelude::*};
+    use usbd_serial::{SerialPort, USB_CLASS_CDC};
+    use cortex_m::peripheral::SCB;
+    use mcp9808::{Alert, Resolution, DEFAULT_ADDRESS};
+
+    /// IWDG timeout, several ticks so a slow flash erase or sensor timeout doesn't trip it,
+    /// and longer than power::MAX_SLEEP since the IWDG keeps counting in STOP mode
+    const WATCHDOG_PERIOD: u32 = 12000;
+
+    #[shared]
+    struct Shared {
+        //console output on USART2, also where replies to UART commands go
+        #[lock_free]
+        tx: SerialOut,
+        //replies to commands received over USB
+        #[lock_free]
+        usb_tx: SerialOut,
+        //both serial ports feed the one command queue
+        #[lock_free]
+        queue: CommandProducer,
+        #[lock_free]
+        display: Lcd,
+        #[lock_free]
+        light: Light,
+        #[lock_free]
+        sensors: Registry,
+        #[lock_free]
+        uptime: u32,
+        #[lock_free]
+        config: Config,
+        #[lock_free]
+        config_dirty: bool,
+        #[lock_free]
+        alarms: Alarms,
+        //backlight state chosen by the user, restored when an alarm stops flashing it
+        #[lock_free]
+        backlight: bool,
+        #[lock_free]
+        alarm_indication: bool,
+        #[lock_free]
+        dashboard: Dashboard,
+        #[lock_free]
+        menu: Menu,
+        //host supplied screen, filled in chunks by DisplayWrite
+        #[lock_free]
+        bitmap: [u8; BITMAP_SIZE],
+        #[lock_free]
+        history: History,
+        #[lock_free]
+        stream: Stream,
+        #[lock_free]
+        mcp9808_settings: Settings,
+        #[lock_free]
+        mcp9808_dirty: bool,
+        #[lock_free]
+        mcp9808_alert: Alert,
+        #[lock_free]
+        mq7_calibrate: bool,
+        #[lock_free]
+        mq7_status: Option<mq7::Status>,
+        //commands lost to a full queue, reported by the next execute run
+        dropped_commands: u16,
+        //set by every execute run, the watchdog is only fed while it keeps being set
+        #[lock_free]
+        execute_alive: bool,
+        //RTC counting seconds from LSE, its alarm drives the tick and wakes up from STOP
+        #[lock_free]
+        rtc: Rtc,
+        //RTC counter at the last tick, moved along when the clock is set so uptime doesn't jump
+        #[lock_free]
+        rtc_counter: u32,
+        power: Power,
+        exti: EXTI,
+    }
+
+    #[local]
+    struct Local {
+        rx: Rx<USART2>,
+        serial_drain: SerialDrain,
+        rx_state: RxState,
+        commands: CommandConsumer,
+        usb_dev: UsbDev,
+        usb_serial: UsbSerial,
+        usb_rx_state: RxState,
+        usb_drain: UsbDrain,
+        //USART2 carries Modbus RTU frames instead of native commands
+        modbus_mode: bool,
+        modbus_rx: Receiver,
+        frames: FrameProducer,
+        requests: FrameConsumer,
+        modbus_address: u8,
+        scb: SCB,
+        timer: CounterHz<TIM2>,
+        button: Button<Pin<'C', 13, Input<Floating>>>,
+        delay: SysDelay,
+        dht: Dht,
+        mq7: Mq7,
+        mcp9808: Mcp9808,
+        analog: AnalogSensor,
+        flash: flash::Parts,
+        config_store: ConfigStore,
+        watchdog: IndependentWatchdog,
+    }
+
+    #[init(local = [command_queue: CommandQueue = CommandQueue::new(), tx_queue: TxQueue = TxQueue::new(),
+        frame_queue: FrameQueue = FrameQueue::new(), usb_tx_queue: TxQueue = TxQueue::new(),
+        adc_buffer: ScanBuffer = [0; SCAN_LENGTH],
+        usb_bus: Option<UsbBusAllocator<UsbBusType>> = None])]
+    fn init(cx: init::Context) -> (Shared, Local, init::Monotonics) {
+        //basic structures
+        let cp = cx.core;
+        let mut dp = cx.device;
+        let reset_cause = ResetCause::read(&dp.RCC);
+        let fault_record = fault::take();
+        let mut flash = dp.FLASH.constrain();
+        let rcc = dp.RCC.constrain();
+        let mut afio = dp.AFIO.constrain();
+
+        //clock configuration
+        let clocks = rcc
+            .cfgr
+            .use_hse(8.MHz())
+            .sysclk(72.MHz())
+            .pclk1(36.MHz())
+            .freeze(&mut flash.acr);
+
+        //persistent configuration, defaults if no valid record is stored
+        let mut config_store = ConfigStore::new();
+        let stored_config = config_store.load(&flash.writer(SectorSize::Sz1K, FlashSize::Sz128K));
+        let config = stored_config.unwrap_or(Config::new());
+
+        //GPIO banks
+        let mut gpioa = dp.GPIOA.split();
+        let mut gpiob = dp.GPIOB.split();
+        let mut gpioc = dp.GPIOC.split();
+
+        let mut delay = cp.SYST.delay(&clocks);
+
+        //RTC on the 32.768 kHz LSE, 1 Hz counter holding Unix time; keeps running through resets on VBAT
+        let mut backup_domain = rcc.bkp.constrain(dp.BKP, &mut dp.PWR);
+        let mut rtc = Rtc::new(dp.RTC, &mut backup_domain);
+
+        //STOP mode with the regulator in low-power mode when idle() sets SLEEPDEEP
+        dp.PWR.cr.modify(|_, w| w.pdds().clear_bit().lpds().set_bit());
+        power::configure_wakeup(&dp.EXTI);
+
+        //SPI configuration & LCD display pins
+        let sck = gpiob.pb13.into_alternate_push_pull(&mut gpiob.crh);
+        let mosi = gpiob.pb15.into_alternate_push_pull(&mut gpiob.crh);
+        let miso = gpiob.pb14.into_floating_input(&mut gpiob.crh);
+        let cs = gpiob.pb10.into_push_pull_output(&mut gpiob.crh);
+
+        let spi_mode = spi::Mode {
+            phase: spi::Phase::CaptureOnFirstTransition,
+            polarity: spi::Polarity::IdleLow,
+        };
+        let spi = Spi::spi2(
+            dp.SPI2,
+            (sck, miso, mosi),
+            spi_mode,
+            4.MHz(),
+            clocks
+        );
+
+        let mut bl = gpioa.pa10.into_push_pull_output(&mut gpioa.crh);
+        let dc = gpioc.pc7.into_push_pull_output(&mut gpioc.crl);
+        let mut rst = gpioa.pa8.into_push_pull_output(&mut gpioa.crh);
+
+        if config.backlight {
+            bl.set_high();
+        }
+
+        //UART configuration
+        let tx_pin = gpioa.pa2.into_alternate_push_pull(&mut gpioa.crl);
+        let rx_pin = gpioa.pa3;
+        let mut serial = Serial::new(
+            dp.USART2,
+            (tx_pin, rx_pin),
+            &mut afio.mapr,
+            serial::Config::default().baudrate(config.baud_rate.bps()).wordlength_8bits().parity_none().stopbits(StopBits::STOP1),
+            &clocks
+        );
+
+        serial.rx.listen();
+        serial.rx.listen_idle();
+
+        //output is buffered and sent by the USART2 interrupt
+        let (tx_producer, tx_consumer) = cx.local.tx_queue.split();
+        let mut tx = SerialOut::new(tx_producer, Interrupt::USART2);
+        //a Modbus master doesn't expect anything but responses on the line
+        tx.set_silent(config.modbus_address != 0);
+
+        //LCD display creation & test
+        let mut display = Pcd8544Spi::new(spi, dc, cs, &mut rst, &mut delay).unwrap();
+        display.set_lcd_coefficients(config.contrast, 0, 4).unwrap();
+
+        writeln!(tx, "Reset cause: {:?}\r\n", reset_cause).unwrap();
+        if let Some(record) = fault_record {
+            writeln!(tx, "Last fault: {:?} pc {:#010x} {}\r\n", record.kind(), record.pc, record.message()).unwrap();
+        }
+
+        if stored_config.is_none() {
+            writeln!(tx, "No stored config, using defaults\r\n").unwrap();
+        }
+
+        let res = display.print(b"Hello world");
+        match res {
+            Ok(_) => writeln!(tx, "Write performed\r\n").unwrap(),
+            Err(_) => writeln!(tx, "Write failed\r\n").unwrap()
+        };
+
+        //DHT11 or DHT22 humidity & temperature sensor configuration
+        let dht_pin = gpiob.pb2.into_open_drain_output(&mut gpiob.crl);
+
+        let mut dht = DhtSensor::new(dht_pin, dht_model(&config));
+        dht.set_offset(config.temperature_offset);
+
+        //ADC1 scans the MQ-7 (A0), spare inputs A1/A2, MCU temperature and VREFINT into memory by DMA
+        let adc = adc::Adc::adc1(dp.ADC1, clocks);
+        let pins = AnalogPins(gpioa.pa0.into_analog(&mut gpioa.crl), gpioa.pa1.into_analog(&mut gpioa.crl),
+            gpioa.pa4.into_analog(&mut gpioa.crl));
+        let dma1 = dp.DMA1.split();
+        let mut analog = AnalogSensor::new(adc, pins, dma1.1, cx.local.adc_buffer);
+
+        //MQ7 configuration - heater switch and digital alarm input
+        let heater = gpiob.pb0.into_push_pull_output(&mut gpiob.crl);
+
+        let mq7_r0 = if config.mq7_r0 > 0 { Some(config.mq7_r0) } else { None };
+        if mq7_r0.is_none() {
+            writeln!(tx, "MQ-7 not calibrated, using default R0\r\n").unwrap();
+        }
+
+        let mut mq7 = Mq7Sensor::new(heater, mq7_r0, 0);
+
+        //let mut mq7_pin = gpioc.pc15.into_floating_input(&mut gpioc.crh);
+
+        //MCP9808 precision temperature sensor on I2C1, remapped to PB8/PB9 (Arduino D15/D14)
+        let scl = gpiob.pb8.into_alternate_open_drain(&mut gpiob.crh);
+        let sda = gpiob.pb9.into_alternate_open_drain(&mut gpiob.crh);
+        let i2c = BlockingI2c::i2c1(
+            dp.I2C1,
+            (scl, sda),
+            &mut afio.mapr,
+            i2c::Mode::Standard { frequency: 100.kHz() },
+            clocks,
+            1000,
+            10,
+            1000,
+            1000
+        );
+
+        let mut mcp9808 = Mcp9808Sensor::new(i2c, DEFAULT_ADDRESS);
+
+        if !mcp9808.probe() {
+            writeln!(tx, "MCP9808 not found\r\n").unwrap();
+        }
+
+        //Nucleo user button on PC13, pulled up on the board; polled at 100 Hz, its falling edge also ends STOP mode
+        let mut user_button = gpioc.pc13.into_floating_input(&mut gpioc.crh);
+        user_button.make_interrupt_source(&mut afio);
+        user_button.trigger_on_edge(&mut dp.EXTI, Edge::Falling);
+        user_button.enable_interrupt(&mut dp.EXTI);
+
+        let mut timer = dp.TIM2.counter_hz(&clocks);
+        timer.start(100.Hz()).unwrap();
+        timer.listen(TimerEvent::Update);
+
+        //sensor registry - every registered sensor is sampled by the tick task and served by ReadSensors
+        let mut sensors = Registry::new();
+        //the analog channels go last, the Modbus map only covers the first MODBUS_CHANNELS
+        let all: [&dyn Sensor; 4] = [&dht, &mq7, &mcp9808, &analog];
+        for sensor in all {
+            if sensors.register(sensor).is_err() {
+                writeln!(tx, "Sensor registry full\r\n").unwrap();
+            }
+        }
+
+        scan_analog(&mut analog, &mut mq7, &config);
+        sample_sensors(&mut [&mut dht, &mut mq7, &mut mcp9808, &mut analog], &mut delay, 0, rtc.current_time(), &mut sensors, &mut tx);
+
+        //commands received by USART2 wait here until execute runs
+        let (queue, commands) = cx.local.command_queue.split();
+        let (frame_producer, frame_consumer) = cx.local.frame_queue.split();
+
+        writeln!(tx, "Please type command |len||cmd||args..|:\r\n").unwrap();
+
+        //the RTC alarm ticks uptime seconds and sampling runs every config.sample_interval seconds
+        let rtc_counter = rtc.current_time();
+        rtc.set_alarm(rtc_counter + 1);
+        rtc.listen_alarm();
+
+        match DateTime::from_timestamp(rtc_counter) {
+            Some(now) => writeln!(tx, "RTC tick started at {}\r\n", now).unwrap(),
+            None => writeln!(tx, "RTC tick started, clock not set\r\n").unwrap(),
+        }
+
+        //USB CDC-ACM serial port on PA11/PA12 carrying the command protocol; 48 MHz USB clock from the 72 MHz PLL.
+        //Holding D+ low for a moment makes the host enumerate the device again after a reset.
+        //USB stops with the PLL in STOP mode, so the low-power profile only suits UART hosts
+        assert!(clocks.usbclk_valid());
+        let mut usb_dp = gpioa.pa12.into_push_pull_output(&mut gpioa.crh);
+        usb_dp.set_low();
+        delay.delay_ms(10u8);
+        let usb = Peripheral {
+            usb: dp.USB,
+            pin_dm: gpioa.pa11,
+            pin_dp: usb_dp.into_floating_input(&mut gpioa.crh),
+        };
+        let usb_bus: &'static _ = cx.local.usb_bus.insert(UsbBus::new(usb));
+        let usb_serial = SerialPort::new(usb_bus);
+        let usb_dev = UsbDeviceBuilder::new(usb_bus, UsbVidPid(0x16c0, 0x27dd))
+            .manufacturer("nucleo-rust")
+            .product("Sensor station")
+            .serial_number("0001")
+            .device_class(USB_CLASS_CDC)
+            .build();
+        let (usb_tx_producer, usb_tx_consumer) = cx.local.usb_tx_queue.split();
+        let usb_tx = SerialOut::new(usb_tx_producer, Interrupt::USB_LP_CAN_RX0);
+
+        //watchdog, fed by the tick task while sampling and command execution keep running
+        let mut watchdog = IndependentWatchdog::new(dp.IWDG);
+        watchdog.start(WATCHDOG_PERIOD.millis());
+
+        (
+            Shared {
+                tx,
+                usb_tx,
+                queue,
+                display,
+                light: bl,
+                sensors,
+                uptime: 0,
+                config,
+                config_dirty: false,
+                alarms: Alarms::new(),
+                backlight: config.backlight,
+                alarm_indication: false,
+                dashboard: Dashboard::new(),
+                menu: Menu::new(),
+                bitmap: [0; BITMAP_SIZE],
+                history: History::new(),
+                stream: Stream::new(),
+                mcp9808_settings: Settings::new(),
+                mcp9808_dirty: true,
+                mcp9808_alert: Alert::default(),
+                mq7_calibrate: false,
+                mq7_status: None,
+                dropped_commands: 0,
+                execute_alive: true,
+                rtc,
+                rtc_counter,
+                power: Power::new(config.low_power),
+                exti: dp.EXTI,
+            },
+            Local {
+                rx: serial.rx,
+                serial_drain: SerialDrain::new(serial.tx, tx_consumer),
+                rx_state: RxState::Length,
+                commands,
+                usb_dev,
+                usb_serial,
+                usb_rx_state: RxState::Length,
+                usb_drain: UsbDrain::new(usb_tx_consumer),
+                modbus_mode: config.modbus_address != 0,
+                modbus_rx: Receiver::new(),
+                frames: frame_producer,
+                requests: frame_consumer,
+                modbus_address: config.modbus_address,
+                scb: cp.SCB,
+                timer,
+                button: Button::new(user_button),
+                delay,
+                dht,
+                mq7,
+                mcp9808,
+                analog,
+                flash,
+                config_store,
+                watchdog,
+            },
+            init::Monotonics(),
+        )
+    }
+
+    /// Sends buffered output, assembles |len||cmd||args..| frames and queues complete commands for `execute`,
+    /// or in Modbus mode queues RTU frames ended by an idle line for `modbus_request`
+    #[task(binds = USART2, priority = 2, local = [rx, rx_state, serial_drain, modbus_mode, modbus_rx, frames],
+        shared = [queue, dropped_commands])]
+    fn usart2(cx: usart2::Context) {
+        cx.local.serial_drain.drain();
+        let rx = cx.local.rx;
+        let rx_state = cx.local.rx_state;
+        if *cx.local.modbus_mode {
+            let receiver = cx.local.modbus_rx;
             while rx.is_rx_not_empty() {
                 if let Ok(received) = nb::block!(rx.read()) {
-                    match RX_STATE {
-                        RxState::Length => {
-                            if received >= 48 && received <= 57 {
-                                let cmd_length = received - 48;
-                                if cmd_length == 0 {
-                                    return;
-                                }
+                    receiver.push(received);
+                }
+                rx.listen_idle();
+            }
+            if rx.is_idle() {
+                rx.unlisten_idle();
+                if let Some(frame) = receiver.end() {
+                    //a master waits for each response, a full queue means it gave up on earlier ones
+                    if cx.local.frames.enqueue(frame).is_ok() {
+                        modbus_request::spawn().ok();
+                    }
+                }
+            }
+            return;
+        }
+        let usart2::SharedResources { queue, mut dropped_commands, .. } = cx.shared;
+        while rx.is_rx_not_empty() {
+            if let Ok(received) = nb::block!(rx.read()) {
+                if let Some(command) = rx_state.push(received, Port::Uart) {
+                    submit(queue, &mut dropped_commands, command);
+                }
+            }
+            rx.listen_idle();
+        }
+        if rx.is_idle() {
+            rx.unlisten_idle();
+        }
+    }
+
+    /// USB CDC-ACM port: runs the device state machine, assembles commands like `usart2` and sends buffered replies
+    #[task(binds = USB_LP_CAN_RX0, priority = 2, local = [usb_dev, usb_serial, usb_rx_state, usb_drain], shared = [queue, dropped_commands])]
+    fn usb(cx: usb::Context) {
+        let usb::LocalResources { usb_dev, usb_serial, usb_rx_state, usb_drain, .. } = cx.local;
+        let usb::SharedResources { queue, mut dropped_commands, .. } = cx.shared;
+        if usb_dev.poll(&mut [usb_serial]) {
+            let mut buffer = [0u8; 64];
+            if let Ok(count) = usb_serial.read(&mut buffer) {
+                for &received in &buffer[..count] {
+                    if let Some(command) = usb_rx_state.push(received, Port::Usb) {
+                        submit(queue, &mut dropped_commands, command);
+                    }
+                }
+            }
+        }
+        usb_drain.drain(usb_serial);
+    }
+
+    /// Queues a received command for `execute`, a full queue counts it as dropped
+    fn submit(queue: &mut CommandProducer, dropped_commands: &mut impl rtic::Mutex<T = u16>, command: Command) {
+        if queue.enqueue(command).is_err() {
+            dropped_commands.lock(|dropped| *dropped = dropped.saturating_add(1));
+        }
+        //already pending is fine, the running drain picks the command up
+        execute::spawn().ok();
+    }
+
+    /// Drains the command queue; same priority as sampling so it never interrupts DHT11 bit timing
+    #[task(priority = 1, local = [commands], shared = [tx, usb_tx, display, light, sensors, config, config_dirty, alarms, backlight,
+        alarm_indication, history, mcp9808_settings, mcp9808_dirty, mcp9808_alert, mq7_calibrate, mq7_status, dropped_commands, execute_alive, uptime, power, rtc, rtc_counter, dashboard, stream, bitmap])]
+    fn execute(cx: execute::Context) {
+        let commands = cx.local.commands;
+        let execute::SharedResources {
+            tx: uart_tx, usb_tx, display, light, sensors, config, config_dirty, alarms, backlight,
+            alarm_indication, history, mcp9808_settings, mcp9808_dirty, mcp9808_alert, mq7_calibrate, mq7_status, mut dropped_commands, execute_alive,
+            uptime, mut power, rtc, rtc_counter, dashboard, stream, bitmap, ..
+        } = cx.shared;
+
+        *execute_alive = true;
+
+        let dropped = dropped_commands.lock(|dropped| core::mem::replace(dropped, 0));
+        if dropped > 0 {
+            writeln!(uart_tx, "Command queue full, {} commands dropped\r", dropped).unwrap();
+        }
+
+        while let Some(command) = commands.dequeue() {
+            power.lock(|power| wake(power, *uptime, display, light, *backlight));
+            //replies go back to the port the command came from
+            let tx = match command.port {
+                Port::Uart => &mut *uart_tx,
+                Port::Usb => &mut *usb_tx,
+            };
+
+            if config.debug {
+                uart_command_response(tx, &command);
+            }
+
+            if command.oversize {
+                writeln!(tx, "Command too long, {} bytes, at most {}\r", command.len, MAX_PAYLOAD + 1).unwrap();
+                continue;
+            }
 
-                                RX_STATE = RxState::Data {
-                                    command: Command::new(cmd_length as usize),
-                                    idx: 0,
+            match command.cmd {
+                CommandCodes::DisplayGas => { //g => read gas
+                    dashboard.hold(*uptime);
+                    show::spawn(Screen::Channel(b'g')).ok();
+                }
+                CommandCodes::DisplayHumidity => { //h => read humidity
+                    dashboard.hold(*uptime);
+                    show::spawn(Screen::Channel(b'h')).ok();
+                }
+                CommandCodes::Info => { //i => firmware, commands, sensors, display and uptime
+                    writeln!(tx, "Firmware {} {} ({})\r", FIRMWARE_NAME, FIRMWARE_VERSION, GIT_HASH).unwrap();
+                    write!(tx, "Commands ").unwrap();
+                    for code in CommandCodes::SUPPORTED {
+                        write!(tx, "{}", code as u8 as char).unwrap();
+                    }
+                    writeln!(tx, ", payload up to {} bytes\r", MAX_PAYLOAD).unwrap();
+                    for channel in sensors.iter() {
+                        match (channel.reading, channel.error) {
+                            (Some(_), None) => writeln!(tx, "Sensor {} {} ok\r", channel.code as char, channel.name).unwrap(),
+                            (_, Some(e)) => writeln!(tx, "Sensor {} {} error {:?}\r", channel.code as char, channel.name, e).unwrap(),
+                            (None, None) => writeln!(tx, "Sensor {} {} not sampled yet\r", channel.code as char, channel.name).unwrap(),
+                        }
+                    }
+                    let (width, height) = display.get_pixel_resolution();
+                    writeln!(tx, "Display {} {}x{}\r", DISPLAY_TYPE, width, height).unwrap();
+                    let up = *uptime;
+                    writeln!(tx, "Uptime {}d {:02}:{:02}:{:02}\r", up / 86400, up / 3600 % 24, up / 60 % 60, up % 60).unwrap();
+                }
+                CommandCodes::Supply => { //u => VDDA from VREFINT and MCU temperature
+                    let style = config.style(false);
+                    match sensors.get(b'v').and_then(|c| c.reading) {
+                        Some(reading) => {
+                            let vdda = reading.value.as_i32();
+                            let range = if (SUPPLY_MIN_MV..=SUPPLY_MAX_MV).contains(&vdda) { "ok" } else { "out of range" };
+                            writeln!(tx, "VDDA {} ({}), analog readings corrected for it\r", reading.value.styled(style), range).unwrap();
+                        }
+                        None => writeln!(tx, "VDDA not measured yet\r").unwrap(),
+                    }
+                    match sensors.get(b'm').and_then(|c| c.reading) {
+                        Some(reading) => writeln!(tx, "MCU temperature {}\r", reading.value.styled(style)).unwrap(),
+                        None => writeln!(tx, "MCU temperature not measured yet\r").unwrap(),
+                    }
+                }
+                CommandCodes::DisplayKris => { //k => changes displayed string
+                    dashboard.hold(*uptime);
+                    show::spawn(Screen::Text(b"Hello Kris")).ok();
+                }
+                CommandCodes::DisplayLightOn => { //l => turn on display's BL
+                    *backlight = true;
+                    light.set_high();
+                }
+                CommandCodes::ConfigurePrecise => { //m => MCP9808 settings, [r,0..3] resolution, [u|l|c,deg] alert limits, none => show
+                    match (command.args.first(), command.args.get(1)) {
+                        (Some(b'r'), Some(&res)) => {
+                            mcp9808_settings.resolution = Resolution::from_bits(res);
+                            *mcp9808_dirty = true;
+                        }
+                        (Some(&limit), Some(&deg)) if limit == b'u' || limit == b'l' || limit == b'c' => {
+                            //limit is a signed whole degree byte
+                            let centi = (deg as i8) as i16 * 100;
+                            match limit {
+                                b'u' => mcp9808_settings.upper = centi,
+                                b'l' => mcp9808_settings.lower = centi,
+                                _ => mcp9808_settings.critical = centi,
+                            }
+                            *mcp9808_dirty = true;
+                        }
+                        _ => {
+                            let s = *mcp9808_settings;
+                            let style = config.style(false);
+                            writeln!(tx, "MCP9808 resolution {} lower {} upper {} critical {}\r", s.resolution as u8,
+                                Quantity::temperature(s.lower as i32, style), Quantity::temperature(s.upper as i32, style),
+                                Quantity::temperature(s.critical as i32, style)).unwrap();
+                            writeln!(tx, "MCP9808 alert lower {} upper {} critical {}\r", mcp9808_alert.lower, mcp9808_alert.upper, mcp9808_alert.critical).unwrap();
+                        }
+                    }
+                }
+                CommandCodes::CalibrateGas => { //z => MQ-7 R0 calibration in clean air, [s] => show status
+                    if command.args.first() == Some(&b's') {
+                        if let Some(status) = *mq7_status {
+                            let phase = match status.phase {
+                                mq7::HeaterPhase::High => "high",
+                                mq7::HeaterPhase::Low => "low",
+                            };
+                            writeln!(tx, "MQ-7 R0 {} ohm{}, heater {} for {}s\r", status.r0,
+                                if status.calibrated { "" } else { " (default)" }, phase, status.remaining).unwrap();
+                            if status.calibrating > 0 {
+                                writeln!(tx, "MQ-7 calibrating, {} heater cycles left\r", status.calibrating).unwrap();
+                            }
+                        }
+                    } else {
+                        *mq7_calibrate = true;
+                        writeln!(tx, "MQ-7 calibration started, keep the sensor in clean air\r").unwrap();
+                    }
+                }
+                CommandCodes::Alarm => { //a => [] show, [k] acknowledge, [t|h|g, l|h|y|d, hi, lo] set low/high/hysteresis/debounce
+                    match (command.args.first(), command.args.get(1), command.reader(2).i16()) {
+                        (Some(b'k'), None, None) => {
+                            if alarms.acknowledge_all() {
+                                update_alarm_indication(alarms, light, display, *backlight, alarm_indication);
+                            }
+                        }
+                        (Some(&code), Some(&kind), Some(value)) => {
+                            let mut new_config = *config;
+                            let valid = match new_config.threshold_mut(code) {
+                                Some(threshold) => match kind {
+                                    b'l' => { threshold.low = value; true }
+                                    b'h' => { threshold.high = value; true }
+                                    b'y' => { threshold.hysteresis = value; true }
+                                    b'd' => { threshold.debounce = value as u8; true }
+                                    _ => false,
+                                },
+                                None => false,
+                            };
+                            if valid && new_config.is_valid() {
+                                *config = new_config;
+                                *config_dirty = true;
+                            } else {
+                                writeln!(tx, "Invalid alarm threshold\r").unwrap();
+                            }
+                        }
+                        _ => {
+                            for alarm in alarms.alarms.iter() {
+                                if let (Some(channel), Some(t)) = (sensors.get(alarm.code), config.threshold(alarm.code)) {
+                                    let state = match alarm.state {
+                                        AlarmState::Normal => "normal",
+                                        AlarmState::Warning => "warning",
+                                        AlarmState::Alarm => "ALARM",
+                                        AlarmState::Acknowledged => "acknowledged",
+                                    };
+                                    writeln!(tx, "{} {}, range {}..{} hysteresis {} debounce {}\r", channel.name, state,
+                                        t.low, t.high, t.hysteresis, t.debounce).unwrap();
+                                }
+                            }
+                        }
+                    }
+                }
+                CommandCodes::History => { //v => [code, tier] statistics, [code, tier, d] dump, [x, n1, n2] set decimation
+                    match (command.args.first(), command.args.get(1), command.args.get(2)) {
+                        (Some(b'x'), Some(&n1), Some(&n2)) => {
+                            if n1 > 0 && n2 > 0 {
+                                history.set_decimation([n1, n2]);
+                            } else {
+                                writeln!(tx, "Invalid decimation\r").unwrap();
+                            }
+                        }
+                        (Some(&code), Some(&tier), dump) => {
+                            //tier as ASCII digit or raw byte
+                            let tier = (tier & 0x0f) as usize;
+                            let (channel_history, channel) = match (history.get(code), sensors.get(code)) {
+                                (Some(channel_history), Some(channel)) if tier < TIERS => (channel_history, channel),
+                                _ => {
+                                    writeln!(tx, "No such history\r").unwrap();
+                                    continue;
+                                }
+                            };
+                            let (stats, reading) = match (channel_history.tiers[tier].stats(), channel.reading) {
+                                (Some(stats), Some(reading)) => (stats, reading),
+                                _ => {
+                                    writeln!(tx, "{} tier {} empty\r", channel.name, tier).unwrap();
+                                    continue;
+                                }
+                            };
+                            let (v, style) = (reading.value, config.style(false));
+                            if dump == Some(&b'd') {
+                                //CSV oldest first, values in the channel's stored unit
+                                writeln!(tx, "{} tier {}, newest at {}s\r", channel.name, tier, channel_history.tiers[tier].last).unwrap();
+                                writeln!(tx, "mean,min,max\r").unwrap();
+                                for entry in channel_history.tiers[tier].iter() {
+                                    writeln!(tx, "{},{},{}\r", entry.mean, entry.min, entry.max).unwrap();
+                                }
+                            } else {
+                                //a spread of temperatures converts like a difference
+                                let stddev = match v {
+                                    Value::Temperature(_) => Quantity::temperature_difference(stats.stddev as i32, style),
+                                    _ => v.like(stats.stddev as i32).styled(style),
                                 };
+                                writeln!(tx, "{} tier {}: {} entries, min {} max {} mean {} stddev {}\r", channel.name, tier, stats.count,
+                                    v.like(stats.min as i32).styled(style), v.like(stats.max as i32).styled(style),
+                                    v.like(stats.mean as i32).styled(style), stddev).unwrap();
+                            }
+                        }
+                        _ => {
+                            writeln!(tx, "Decimation {}/{}\r", history.decimation[0], history.decimation[1]).unwrap();
+                        }
+                    }
+                }
+                CommandCodes::Config => { //c => [] show, [d] reset to defaults, [field,hi,lo] set field to a big-endian u16
+                    match (command.args.first(), command.args.get(1), command.reader(1).u16()) {
+                        (Some(b'd'), None, None) => {
+                            *config = Config::new();
+                            *config_dirty = true;
+                        }
+                        (Some(&field), Some(_), Some(value)) => {
+                            if config.set(field, value) {
+                                *config_dirty = true;
+                                if field == b'k' {
+                                    display.set_lcd_coefficients(config.contrast, 0, 4).unwrap();
+                                }
                             } else {
-                                RX_STATE = RxState::Length;
+                                writeln!(tx, "Invalid config value\r").unwrap();
                             }
                         }
-
-                        RxState::Data { ref mut command, ref mut idx } => {
-                            if *idx == 0 {
-                                match received {
-                                    103 => command.cmd = CommandCodes::DisplayGas,
-                                    104 => command.cmd = CommandCodes::DisplayHumidity,
-                                    107 => command.cmd = CommandCodes::DisplayKris,
-                                    108 => command.cmd = CommandCodes::DisplayLightOn,
-                                    114 => command.cmd = CommandCodes::ReadSensors,
-                                    115 => command.cmd = CommandCodes::DisplayLightOff,
-                                    116 => command.cmd = CommandCodes::DisplayTemperature,
-                                    _ => command.cmd = CommandCodes::NoCommand
+                        _ => {
+                            let (c, style) = (*config, config.style(false));
+                            writeln!(tx, "Baud rate {} (after reset), sample interval {}s\r", c.baud_rate, c.sample_interval).unwrap();
+                            writeln!(tx, "Contrast {}, backlight {}, debug {}\r", c.contrast, c.backlight, c.debug).unwrap();
+                            writeln!(tx, "Temperature alarm {}..{}, offset {}\r", Value::Temperature(c.temperature_alarm.low).styled(style),
+                                Value::Temperature(c.temperature_alarm.high).styled(style),
+                                Quantity::temperature_difference(c.temperature_offset as i32, style)).unwrap();
+                            writeln!(tx, "Humidity alarm {}..{}\r", Value::Humidity(c.humidity_alarm.low as u16).styled(style),
+                                Value::Humidity(c.humidity_alarm.high as u16).styled(style)).unwrap();
+                            writeln!(tx, "Gas alarm {}..{}, MQ-7 R0 {} ohm\r", Value::Gas(c.gas_alarm.low as u16).styled(style),
+                                Value::Gas(c.gas_alarm.high as u16).styled(style), c.mq7_r0).unwrap();
+                            writeln!(tx, "Low power {}, dashboard every {}s, pages {:016b}\r", c.low_power,
+                                c.dashboard_interval, c.dashboard_pages).unwrap();
+                            writeln!(tx, "Modbus address {} (after reset, 0 = command protocol), {}\r", c.modbus_address,
+                                if c.dht22 { "DHT22" } else { "DHT11" }).unwrap();
+                            writeln!(tx, "Readings in {} with {} decimals\r", if c.fahrenheit { "°F" } else { "°C" }, c.decimals).unwrap();
+                            let [a1, a2] = c.analog_calibration;
+                            writeln!(tx, "Analog oversampling {}, A1 x{}/1000 {:+}, A2 x{}/1000 {:+}\r", c.adc_oversample,
+                                a1.gain, a1.offset, a2.gain, a2.offset).unwrap();
+                        }
+                    }
+                }
+                CommandCodes::ReadSensors => { //r => read measurements, args are channel codes, e.g. [g,h,t], or derived metrics d/i/w/f
+                    let style = config.style(false);
+                    for i in 0..command.args.len() {
+                        let metric = match command.args[i] {
+                            b'd' => Some(("Dew point", sensors.climate().map(|c| Value::Temperature(c.dew_point)))),
+                            b'i' => Some(("Heat index", sensors.climate().map(|c| Value::Temperature(c.heat_index)))),
+                            b'w' => Some(("Absolute humidity", sensors.climate().map(|c| Value::AbsoluteHumidity(c.absolute_humidity)))),
+                            _ => None,
+                        };
+                        if let Some((name, value)) = metric { //d/i/w => dew point, heat index, absolute humidity
+                            match value {
+                                Some(value) => writeln!(tx, "{} is {}\r", name, value.styled(style)).unwrap(),
+                                None => writeln!(tx, "{} needs temperature and humidity\r", name).unwrap(),
+                            }
+                        } else if command.args[i] == b'f' { //f => comfort level
+                            match sensors.climate() {
+                                Some(climate) => writeln!(tx, "Comfort is {}\r", climate.comfort.name()).unwrap(),
+                                None => writeln!(tx, "Comfort needs temperature and humidity\r").unwrap(),
+                            }
+                        } else if command.args[i] == b'c' { //c => DHT11 vs MCP9808 cross-check
+                            if let (Some(coarse), Some(precise)) = (sensors.get(b't').and_then(|c| c.reading), sensors.get(b'p').and_then(|c| c.reading)) {
+                                let diff = Quantity::temperature_difference(coarse.value.centi() - precise.value.centi(), style);
+                                writeln!(tx, "DHT {} MCP9808 {} diff {}\r", coarse.value.styled(style), precise.value.styled(style), diff).unwrap();
+                            }
+                        } else if let Some(channel) = sensors.get(command.args[i]) {
+                            match (channel.reading, channel.error) {
+                                (Some(reading), None) => match DateTime::from_timestamp(reading.time) {
+                                    Some(time) => writeln!(tx, "{} is {} at {}\r", channel.name, reading.value.styled(style), time).unwrap(),
+                                    None => writeln!(tx, "{} is {}\r", channel.name, reading.value.styled(style)).unwrap(),
+                                },
+                                (Some(reading), Some(e)) => match DateTime::from_timestamp(reading.time) {
+                                    Some(time) => writeln!(tx, "{} is {} (stale since {}, {:?})\r", channel.name, reading.value.styled(style), time, e).unwrap(),
+                                    None => writeln!(tx, "{} is {} (stale since {}s, {:?})\r", channel.name, reading.value.styled(style), reading.timestamp, e).unwrap(),
+                                },
+                                (None, Some(e)) => {
+                                    writeln!(tx, "{} error: {:?}\r", channel.name, e).unwrap();
+                                }
+                                (None, None) => {
+                                    writeln!(tx, "{} not sampled yet\r", channel.name).unwrap();
                                 }
+                            }
+                        }
+                    }
+                }
+                CommandCodes::Stream => { //o => [] show, [x] stop, [t|c|j|b, interval] stream text/CSV/JSON/binary records every interval seconds
+                    match (command.args.first(), command.args.get(1)) {
+                        (Some(b'x'), None) => stream.stop(),
+                        (Some(&format), Some(&interval)) => match Format::from_code(format) {
+                            Some(format) if interval > 0 => stream.start(format, interval as u16, *uptime, command.port),
+                            _ => writeln!(tx, "Invalid stream format or interval\r").unwrap(),
+                        },
+                        _ => {
+                            if stream.is_active() {
+                                writeln!(tx, "Streaming {} every {}s over {:?}, next seq {}\r", stream.format.name(), stream.interval,
+                                    stream.port, stream.seq).unwrap();
                             } else {
-                                command.args.push(received as u8).unwrap();
+                                writeln!(tx, "Streaming stopped\r").unwrap();
+                            }
+                        }
+                    }
+                }
+                CommandCodes::Dashboard => { //b => [] show, [p, n] pin page n, [s] pause, [g] resume, [n] next page
+                    match (command.args.first(), command.args.get(1)) {
+                        (Some(b'p'), Some(&page)) => {
+                            //page as ASCII digit or raw byte
+                            match dashboard.pin((page & 0x0f) as usize) {
+                                Some(page) => { show::spawn(Screen::Page(page)).ok(); }
+                                None => writeln!(tx, "No such page\r").unwrap(),
+                            }
+                        }
+                        (Some(b's'), None) => dashboard.paused = true,
+                        (Some(b'g'), None) => {
+                            //host content gives way at once, a page on screen keeps its interval
+                            if dashboard.host {
+                                show::spawn(Screen::Page(dashboard.current())).ok();
+                            }
+                            dashboard.resume(*uptime);
+                        }
+                        (Some(b'n'), None) => {
+                            let page = dashboard.next(*uptime, config.dashboard_pages);
+                            show::spawn(Screen::Page(page)).ok();
+                        }
+                        _ => {
+                            writeln!(tx, "Dashboard page {}{}{}{}, every {}s, pages {:016b}\r", dashboard.page,
+                                if dashboard.pinned { " pinned" } else { "" }, if dashboard.paused { " paused" } else { "" },
+                                if dashboard.host { " under host content" } else { "" },
+                                config.dashboard_interval, config.dashboard_pages).unwrap();
+                        }
+                    }
+                }
+                CommandCodes::Clock => { //d => [] show, [d, year - 2000, month, day] set date, [t, hour, minute, second] set time
+                    let current = DateTime::from_timestamp(rtc.current_time());
+                    match (command.args.first(), command.args.get(1), command.args.get(2), command.args.get(3)) {
+                        (Some(&field), Some(&a), Some(&b), Some(&c)) if field == b'd' || field == b't' => {
+                            //setting only the time keeps the date, a clock that was never set starts from 2020-01-01
+                            let mut time = current.unwrap_or(DateTime { year: 2020, month: 1, day: 1, hour: 0, minute: 0, second: 0 });
+                            if field == b'd' {
+                                time.year = 2000 + a as u16;
+                                time.month = b;
+                                time.day = c;
+                            } else {
+                                time.hour = a;
+                                time.minute = b;
+                                time.second = c;
+                            }
+                            if time.is_valid() {
+                                let counter = time.timestamp();
+                                rtc.set_time(counter);
+                                *rtc_counter = counter;
+                                rtc.set_alarm(counter + 1);
+                                show::spawn(Screen::Time).ok();
+                            } else {
+                                writeln!(tx, "Invalid date or time\r").unwrap();
+                            }
+                        }
+                        _ => match current {
+                            Some(time) => writeln!(tx, "Time {}\r", time).unwrap(),
+                            None => writeln!(tx, "Clock not set\r").unwrap(),
+                        }
+                    }
+                }
+                CommandCodes::Power => { //p => [] show, [f] performance profile, [l] low-power profile
+                    match command.args.first() {
+                        Some(&profile) if profile == b'f' || profile == b'l' => {
+                            config.low_power = profile == b'l';
+                            *config_dirty = true;
+                        }
+                        _ => {
+                            let (idle, display_off) = power.lock(|power| (power.is_idle(*uptime), power.display_off));
+                            writeln!(tx, "Profile {}, idle {}, display off {}\r",
+                                if config.low_power { "low-power" } else { "performance" }, idle, display_off).unwrap();
+                        }
+                    }
+                }
+                CommandCodes::DisplayLightOff => { //s => turn off display's BL
+                    *backlight = false;
+                    light.set_low();
+                }
+                CommandCodes::DisplayWrite => { //w => [t, row, column, text..] print, [c] clear, [c, row, column, columns, rows] clear a region,
+                    //[i, 0|1] normal/inverse, [k, contrast] adjust contrast, [b, offset hi, offset lo, data..] bitmap chunk, [d] draw bitmap
+                    //text, clears and the bitmap stay up until a dashboard command or a button press
+                    let args = &command.args;
+                    match (args.first(), args.get(1), args.get(2)) {
+                        (Some(b't'), Some(&row), Some(&column)) if row < 6 && column < 14 => match command.reader(3).str() {
+                            Some(text) => {
+                                dashboard.show_host();
+                                display.set_position(column * 6, row).unwrap();
+                                //the font has no glyphs outside printable ASCII
+                                for c in text.chars() {
+                                    display.print_char(if (' '..'\u{80}').contains(&c) { c as u8 } else { b'?' }).unwrap();
+                                }
                             }
-                            *idx += 1;
-                            if *idx == command.len {
-                                command.copy(&mut CURRENT_COMMAND);
-                                RX_STATE = RxState::Length;
-                                execute_command();
-                                if DEBUG_MODE {
-                                    uart_command_response();
+                            None => writeln!(tx, "Text is not UTF-8\r").unwrap(),
+                        },
+                        (Some(b'c'), None, None) => {
+                            dashboard.show_host();
+                            display.clear().unwrap();
+                        }
+                        (Some(b'c'), Some(&row), Some(&column)) if args.len() == 5 && row < 6 && column < 14 => {
+                            dashboard.show_host();
+                            let mut size = command.reader(3);
+                            let (columns, rows) = (size.u8().unwrap_or(0), size.u8().unwrap_or(0));
+                            for r in row..row.saturating_add(rows).min(6) {
+                                display.set_position(column * 6, r).unwrap();
+                                for _ in column..column.saturating_add(columns).min(14) {
+                                    display.print_char(b' ').unwrap();
                                 }
                             }
                         }
+                        (Some(b'i'), Some(&inverse), None) => {
+                            display.set_mode(if inverse & 1 != 0 { Modes::Inverse } else { Modes::Normal }).unwrap();
+                        }
+                        (Some(b'k'), Some(&contrast), None) if contrast < 91 => {
+                            display.set_lcd_coefficients(contrast, 0, 4).unwrap();
+                        }
+                        (Some(b'b'), Some(_), Some(_)) => {
+                            let mut chunk = command.reader(1);
+                            let offset = chunk.u16().map_or(0, |offset| offset as usize);
+                            let data = chunk.bytes();
+                            match bitmap.get_mut(offset..offset + data.len()) {
+                                Some(chunk) => chunk.copy_from_slice(data),
+                                None => writeln!(tx, "Bitmap chunk out of range\r").unwrap(),
+                            }
+                        }
+                        (Some(b'd'), None, None) => {
+                            dashboard.show_host();
+                            show::spawn(Screen::Bitmap).ok();
+                        }
+                        _ => writeln!(tx, "Invalid display command\r").unwrap(),
                     }
                 }
-                rx.listen_idle();
+                CommandCodes::DisplayTemperature => { //t => read temperature
+                    dashboard.hold(*uptime);
+                    show::spawn(Screen::Channel(b't')).ok();
+                }
+                _ => {}
             }
-            if rx.is_idle() {
-                rx.unlisten_idle();
+        }
+    }
+
+    /// Answers queued Modbus RTU requests, at the priority of the tasks owning the register data
+    #[task(priority = 1, local = [requests, modbus_address], shared = [tx, display, light, sensors, config, config_dirty, backlight,
+        dashboard, uptime, power])]
+    fn modbus_request(cx: modbus_request::Context) {
+        let modbus_request::LocalResources { requests, modbus_address, .. } = cx.local;
+        let modbus_request::SharedResources {
+            tx, display, light, sensors, config, config_dirty, backlight, dashboard, uptime, mut power, ..
+        } = cx.shared;
+
+        while let Some(request) = requests.dequeue() {
+            power.lock(|power| wake(power, *uptime, display, light, *backlight));
+            let mut map = ModbusMap { sensors, config, config_dirty, backlight, light, display, dashboard, uptime: *uptime, page: None };
+            if let Some(response) = modbus::handle(&request, *modbus_address, &mut map) {
+                tx.write_all(&response);
+            }
+            if let Some(page) = map.page {
+                show::spawn(Screen::Page(page)).ok();
             }
         }
-    })
-}
+    }
 
-#[entry]
-fn main() -> ! {
-    //basic structures
-    let cp = cortex_m::Peripherals::take().unwrap();
-    let dp = pac::Peripherals::take().unwrap();
-    let mut flash = dp.FLASH.constrain();
-    let rcc = dp.RCC.constrain();
-    let mut afio = dp.AFIO.constrain();
-
-    //clock configuration
-    let clocks = rcc
-        .cfgr
-        .use_hse(8.MHz())
-        .sysclk(72.MHz())
-        .pclk1(36.MHz())
-        .freeze(&mut flash.acr);
-
-    //GPIO banks
-    let mut gpioa = dp.GPIOA.split();
-    let mut gpiob = dp.GPIOB.split();
-    let mut gpioc = dp.GPIOC.split();
-
-    let mut delay = cp.SYST.delay(&clocks);
-
-    //timer configuration
-    let mut timer = dp.TIM2.counter_hz(&clocks);
-
-    //SPI configuration & LCD display pins
-    let sck = gpiob.pb13.into_alternate_push_pull(&mut gpiob.crh);
-    let mosi = gpiob.pb15.into_alternate_push_pull(&mut gpiob.crh);
-    let miso = gpiob.pb14.into_floating_input(&mut gpiob.crh);
-    let cs = gpiob.pb10.into_push_pull_output(&mut gpiob.crh);
-
-    let spi_mode = spi::Mode {
-        phase: spi::Phase::CaptureOnFirstTransition,
-        polarity: spi::Polarity::IdleLow,
-    };
-    let spi = Spi::spi2(
-        dp.SPI2,
-        (sck, miso, mosi),
-        spi_mode,
-        4.MHz(),
-        clocks
-    );
-
-    let mut bl = gpioa.pa10.into_push_pull_output(&mut gpioa.crh);
-    let dc = gpioc.pc7.into_push_pull_output(&mut gpioc.crl);
-    let mut rst = gpioa.pa8.into_push_pull_output(&mut gpioa.crh);
-
-    bl.set_high();
-
-    //UART configuration
-    let tx_pin = gpioa.pa2.into_alternate_push_pull(&mut gpioa.crl);
-    let rx_pin = gpioa.pa3;
-    let mut serial = Serial::new(
-        dp.USART2,
-        (tx_pin, rx_pin),
-        &mut afio.mapr,
-        Config::default().baudrate(115200.bps()).wordlength_8bits().parity_none().stopbits(StopBits::STOP1),
-        &clocks
-    );
-
-    serial.tx.listen();
-    serial.rx.listen();
-    serial.rx.listen_idle();
-
-    //LCD display creation & test
-    let mut display = Pcd8544Spi::new(spi, dc, cs, &mut rst, &mut delay).unwrap();
-
-    let res = display.print(b"Hello world");
-    match res {
-        Ok(_) => writeln!(serial.tx, "Write performed\r\n").unwrap(),
-        Err(_) => writeln!(serial.tx, "Write failed\r\n").unwrap()
-    };
-
-    //DHT11 humidity & temperature sensor configuration
-    let dht11_pin = gpiob.pb2.into_open_drain_output(&mut gpiob.crl);
-
-    let mut dht11 = Dht11::new(dht11_pin);
-
-    let mut measurement = Measurement {temperature: 0i16, humidity: 0u16};
-
-    match dht11.perform_measurement(&mut delay) {
-        Ok(msrmt) => measurement = msrmt,
-        Err(e) => writeln!(serial.tx, "Error: {:?}\r\n", e).unwrap(),
-    };
-
-    //MQ7 configuration - ADC and digital alarm input
-    let mut adc = adc::Adc::adc1(dp.ADC1, clocks);
-    let mut ch0 = gpioa.pa0.into_analog(&mut gpioa.crl);
-
-    //let mut mq7_pin = gpioc.pc15.into_floating_input(&mut gpioc.crh);
-
-    writeln!(serial.tx, "Please type command |len||cmd||args..|:\r\n").unwrap();
-
-    //start timer
-    timer.start(1.Hz()).unwrap();
-
-    writeln!(serial.tx, "Timer started\r\n").unwrap();
-
-    //static global variables write
-    cortex_m::interrupt::free(|_| unsafe {
-        TX.replace(serial.tx);
-        RX.replace(serial.rx);
-        DISPLAY.replace(display);
-        LIGHT.replace(bl);
-        DHT11_MEASUREMENT.replace(measurement);
-    });
-
-    //enable interrupts
-    unsafe {
-        cortex_m::peripheral::NVIC::unmask(pac::Interrupt::USART2);
+    /// Redraws the LCD, display work is kept out of the receive path
+    #[task(priority = 1, capacity = 6, shared = [display, sensors, history, alarms, menu, bitmap, config, backlight, mq7_status, uptime, rtc, dashboard])]
+    fn show(cx: show::Context, screen: Screen) {
+        let show::SharedResources { display, sensors, history, alarms, menu, bitmap, config, backlight, mq7_status, uptime, rtc, dashboard, .. } = cx.shared;
+        let view = View {
+            sensors,
+            history,
+            alarms,
+            menu,
+            bitmap,
+            style: config.style(true),
+            backlight: *backlight,
+            mq7_status: *mq7_status,
+            uptime: *uptime,
+            time: rtc.current_time(),
+            host: dashboard.host,
+        };
+        render(display, screen, &view);
     }
 
-    unsafe {
-        if let Some(serial_tx) = TX.as_mut() {
-            writeln!(serial_tx, "Interrupts enabled\r\n").unwrap();
+    /// Sleeps between interrupts, in STOP mode while the low-power profile is idle
+    #[idle(local = [scb], shared = [power, exti])]
+    fn idle(mut cx: idle::Context) -> ! {
+        loop {
+            cortex_m::interrupt::free(|_| {
+                let stop = cx.shared.power.lock(|power| power.stop);
+                if stop {
+                    cx.shared.exti.lock(|exti| power::listen_rx_wakeup(exti, true));
+                    cx.local.scb.set_sleepdeep();
+                } else {
+                    cx.local.scb.clear_sleepdeep();
+                }
+                //wakes up on a pending interrupt, which runs once interrupts are enabled again below
+                cortex_m::asm::wfi();
+                if stop {
+                    power::restore_clocks();
+                    cx.shared.exti.lock(|exti| power::listen_rx_wakeup(exti, false));
+                }
+            });
         }
     }
 
-    loop {
-        timer.wait().unwrap();
-        unsafe {
-            MQ7_MEASUREMENT = adc.read(&mut ch0).unwrap();
-            match dht11.perform_measurement(&mut delay) {
-                Ok(msrmt) => {
-                    if let Some(meas) = DHT11_MEASUREMENT.as_mut() {
-                        *meas = msrmt;
-                    }
+    /// UART activity during STOP mode; the byte that woke the device up is lost
+    #[task(binds = EXTI3, priority = 1, shared = [display, light, backlight, uptime, rtc, power, exti])]
+    fn wake_up(cx: wake_up::Context) {
+        let wake_up::SharedResources { display, light, backlight, uptime, rtc, mut power, mut exti, .. } = cx.shared;
+        exti.lock(|exti| power::listen_rx_wakeup(exti, false));
+        power.lock(|power| wake(power, *uptime, display, light, *backlight));
+        //tick every second again instead of waiting for the long sleep alarm
+        rtc.set_alarm(rtc.current_time() + 1);
+    }
+
+    /// Button press during STOP mode, polling takes over once the clocks run again
+    #[task(binds = EXTI15_10, priority = 1, shared = [rtc, power, exti])]
+    fn button_wake(cx: button_wake::Context) {
+        let button_wake::SharedResources { rtc, mut power, mut exti, .. } = cx.shared;
+        exti.lock(|exti| power::clear_button_wakeup(exti));
+        if power.lock(|power| core::mem::replace(&mut power.stop, false)) {
+            rtc.set_alarm(rtc.current_time() + 1);
+        }
+    }
+
+    /// Polls the user button at 100 Hz and drives the menu
+    #[task(binds = TIM2, priority = 1, local = [timer, button],
+        shared = [display, light, backlight, sensors, uptime, config, config_dirty, mq7_calibrate, menu, dashboard, power])]
+    fn buttons(cx: buttons::Context) {
+        let buttons::LocalResources { timer, button, .. } = cx.local;
+        let buttons::SharedResources {
+            display, light, backlight, sensors, uptime, config, config_dirty, mq7_calibrate, menu, dashboard, mut power, ..
+        } = cx.shared;
+
+        timer.clear_interrupt(TimerEvent::Update);
+        let press = match button.poll() {
+            Some(press) => press,
+            None => return,
+        };
+
+        let display_off = power.lock(|power| {
+            let display_off = power.display_off;
+            wake(power, *uptime, display, light, *backlight);
+            display_off
+        });
+        //the press that wakes the display up only shows it again
+        if display_off {
+            return;
+        }
+        //and one on host content brings the dashboard back
+        if dashboard.host {
+            dashboard.release(*uptime);
+            show::spawn(Screen::Page(dashboard.current())).ok();
+            return;
+        }
+
+        match menu.press(press, *uptime, sensors.iter().count(), config.contrast) {
+            Action::None => {}
+            Action::ToggleBacklight => {
+                *backlight = !*backlight;
+                if *backlight {
+                    light.set_high();
+                } else {
+                    light.set_low();
                 }
-                Err(e) => {
-                    if let Some(serial_tx) = TX.as_mut() {
-                        writeln!(serial_tx, "Error: {:?}\r\n", e).unwrap();
-                    }
+            }
+            Action::Contrast(contrast) => {
+                display.set_lcd_coefficients(contrast, 0, 4).unwrap();
+            }
+            Action::SaveContrast(contrast) => {
+                if config.set(b'k', contrast as u16) {
+                    *config_dirty = true;
                 }
+            }
+            Action::Calibrate => *mq7_calibrate = true,
+            Action::Close => {
+                show::spawn(Screen::Page(dashboard.current())).ok();
+                return;
+            }
+        }
+        show::spawn(Screen::Menu).ok();
+    }
+
+    /// RTC alarm tick, every second or up to power::MAX_SLEEP when idle;
+    /// samples sensors every config.sample_interval seconds
+    #[task(binds = RTCALARM, priority = 1, local = [delay, dht, mq7, mcp9808, analog, flash, config_store, watchdog, last_sample: u32 = 0],
+        shared = [tx, usb_tx, display, light, sensors, uptime, config, config_dirty, alarms, backlight, alarm_indication, history,
+        mcp9808_settings, mcp9808_dirty, mcp9808_alert, mq7_calibrate, mq7_status, execute_alive, rtc, rtc_counter, power, exti, dashboard, menu, stream])]
+    fn tick(cx: tick::Context) {
+        let tick::LocalResources { delay, dht, mq7, mcp9808, analog, flash, config_store, watchdog, last_sample, .. } = cx.local;
+        let tick::SharedResources {
+            tx, usb_tx, display, light, sensors, uptime, config, config_dirty, alarms, backlight, alarm_indication, history,
+            mcp9808_settings, mcp9808_dirty, mcp9808_alert, mq7_calibrate, mq7_status, execute_alive, rtc, rtc_counter, mut power, mut exti, dashboard, menu, stream, ..
+        } = cx.shared;
+
+        rtc.clear_alarm_flag();
+        exti.lock(|exti| power::clear_rtc_alarm(exti));
+        let counter = rtc.current_time();
+        *uptime += counter.wrapping_sub(*rtc_counter);
+        //the clock corner changes every minute
+        if counter / 60 != *rtc_counter / 60 {
+            show::spawn(Screen::Time).ok();
+        }
+        *rtc_counter = counter;
+        let now = *uptime;
+
+        //apply MCP9808 settings changed by commands
+        if core::mem::replace(mcp9808_dirty, false) {
+            if let Err(e) = mcp9808.configure(mcp9808_settings) {
+                writeln!(tx, "MCP9808 configuration error: {:?}\r\n", e).unwrap();
+            }
+        }
+
+        if core::mem::replace(mq7_calibrate, false) {
+            mq7.start_calibration();
+        }
+
+        //a failed DHT read is retried on its own between regular samples
+        let due = now.wrapping_sub(*last_sample) >= config.sample_interval as u32;
+        let dht_due = due || dht.retry_due(now);
+        //the MQ-7 heater cycle runs on RTC seconds, its reading is taken when the low phase ends whatever the interval
+        let gas_due = mq7.advance(now);
+        let sampled = dht_due || gas_due;
+        let mut alarm_screen = None;
+        if sampled {
+            if dht_due {
+                dht.set_offset(config.temperature_offset);
+                dht.set_model(dht_model(config));
+                //the USB interrupt is above this priority and would stretch the DHT bit timing
+                crate::usb::without_interrupt(|| sample_sensors(&mut [&mut *dht], delay, now, counter, sensors, tx));
+            }
+            if due || gas_due {
+                scan_analog(analog, mq7, config);
+            }
+            if due {
+                *last_sample = now;
+                sample_sensors(&mut [mq7, mcp9808, analog], delay, now, counter, sensors, tx);
+            } else if gas_due {
+                sample_sensors(&mut [mq7], delay, now, counter, sensors, tx);
+            }
+            alarm_screen = check_alarms(alarms, sensors, config, tx, now);
+            record_history(history, sensors, now);
+        }
+
+        //the menu owns the display while open, the dashboard comes back when it closes
+        if menu.expire(now) {
+            show::spawn(Screen::Page(dashboard.current())).ok();
+        } else if !menu.is_open() {
+            if let Some(page) = dashboard.update(now, config.dashboard_interval, config.dashboard_pages, sampled) {
+                show::spawn(Screen::Page(page)).ok();
+            }
+        }
+        if let Some(screen) = alarm_screen {
+            dashboard.hold(now);
+            show::spawn(screen).ok();
+        }
+        update_alarm_indication(alarms, light, display, *backlight, alarm_indication);
+
+        if let Some(r0) = mq7.take_calibration() {
+            config.mq7_r0 = r0;
+            *config_dirty = true;
+            writeln!(tx, "MQ-7 calibrated, R0 {} ohm\r\n", r0).unwrap();
+        }
+
+        *mq7_status = Some(mq7.status(now));
+        *mcp9808_alert = mcp9808.alert();
+
+        //persist configuration changed by commands or calibration
+        if core::mem::replace(config_dirty, false) {
+            match config_store.save(&mut flash.writer(SectorSize::Sz1K, FlashSize::Sz128K), config) {
+                Ok(_) => writeln!(tx, "Config saved\r\n").unwrap(),
+                Err(e) => writeln!(tx, "Config save failed: {:?}\r\n", e).unwrap(),
+            }
+        }
+
+        //a record that doesn't fit in the UART buffer is dropped whole, the sequence gap shows it
+        if let Some(record) = stream.record(sensors, config.style(false), now, counter) {
+            match stream.port {
+                Port::Uart => tx.write_all(&record),
+                Port::Usb => usb_tx.write_all(&record),
             };
         }
+
+        let overflow = tx.take_overflow();
+        if overflow > 0 {
+            writeln!(tx, "UART buffer full, {} bytes dropped\r\n", overflow).unwrap();
+        }
+        let overflow = usb_tx.take_overflow();
+        if overflow > 0 {
+            writeln!(usb_tx, "USB buffer full, {} bytes dropped\r\n", overflow).unwrap();
+        }
+
+        //this tick finished and execute ran since the last one, then ask execute for the next heartbeat
+        if core::mem::replace(execute_alive, false) {
+            watchdog.feed();
+        }
+        execute::spawn().ok();
+
+        //an active alarm keeps the display and the 1 s tick running
+        let period = power.lock(|power| {
+            power.low_power = config.low_power;
+            if alarms.active() {
+                wake(power, now, display, light, *backlight);
+            }
+            if power.is_idle(now) && !power.display_off {
+                light.set_low();
+                display.set_power_down(true).unwrap();
+                power.display_off = true;
+            }
+            power.stop = power.is_idle(now);
+            //streaming keeps ticking often enough for its records
+            let interval = if stream.is_active() { config.sample_interval.min(stream.interval) } else { config.sample_interval };
+            //waking up for the next heater phase change keeps the MQ-7 cycle to its length
+            power.tick_period(now, interval).min(mq7.status(now).remaining.max(1))
+        });
+        rtc.set_alarm(counter + period);
+
+        //USART2 stops in STOP mode, let the buffered output go out first
+        if power.lock(|power| power.stop) {
+            while !tx.is_empty() {}
+        }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,158 @@
+use core::fmt::Write;
+use heapless::String;
+use lcd_hal::Display;
+
+use crate::{FIRMWARE_VERSION, Lcd};
+use crate::button::Press;
+use crate::fixed::Style;
+use crate::sensor::{Registry, mq7};
+
+/// Seconds without a press before the menu closes and the dashboard takes over again
+pub const MENU_TIMEOUT: u32 = 30;
+
+const ITEMS: [&str; 6] = ["Readings", "Backlight", "Contrast", "Calibrate MQ7", "Info", "Exit"];
+const CONTRAST_MIN: u8 = 30;
+const CONTRAST_MAX: u8 = 90;
+const CONTRAST_STEP: u8 = 4;
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum State {
+    Closed,
+    /// selected item
+    List(usize),
+    /// registry index of the shown channel
+    Readings(usize),
+    Contrast,
+    Info,
+}
+
+/// What a press asks the firmware to do besides redrawing the menu
+#[derive(Copy, Clone, PartialEq)]
+pub enum Action {
+    None,
+    ToggleBacklight,
+    /// preview a contrast value
+    Contrast(u8),
+    /// keep the previewed contrast in the config
+    SaveContrast(u8),
+    Calibrate,
+    Close,
+}
+
+/// Single button menu: a short press moves on, a long press selects or goes back
+pub struct Menu {
+    pub state: State,
+    pub contrast: u8,
+    /// uptime of the last press
+    pub last_press: u32,
+}
+
+impl Menu {
+    pub const fn new() -> Menu {
+        Menu { state: State::Closed, contrast: 0, last_press: 0 }
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.state != State::Closed
+    }
+
+    /// `contrast` is the configured value, the starting point for the contrast editor
+    pub fn press(&mut self, press: Press, now: u32, channels: usize, contrast: u8) -> Action {
+        self.last_press = now;
+        let (state, action) = match (self.state, press) {
+            (State::Closed, _) => (State::List(0), Action::None),
+            (State::List(item), Press::Short) => (State::List((item + 1) % ITEMS.len()), Action::None),
+            (State::List(item), Press::Long) => match item {
+                0 => (State::Readings(0), Action::None),
+                1 => (State::List(item), Action::ToggleBacklight),
+                2 => {
+                    self.contrast = contrast;
+                    (State::Contrast, Action::None)
+                }
+                3 => (State::List(item), Action::Calibrate),
+                4 => (State::Info, Action::None),
+                _ => (State::Closed, Action::Close),
+            },
+            (State::Readings(channel), Press::Short) => (State::Readings((channel + 1) % channels.max(1)), Action::None),
+            (State::Contrast, Press::Short) => {
+                self.contrast = if self.contrast + CONTRAST_STEP > CONTRAST_MAX { CONTRAST_MIN } else { self.contrast + CONTRAST_STEP };
+                (State::Contrast, Action::Contrast(self.contrast))
+            }
+            (State::Contrast, Press::Long) => (State::List(2), Action::SaveContrast(self.contrast)),
+            (State::Readings(_), Press::Long) => (State::List(0), Action::None),
+            (State::Info, _) => (State::List(4), Action::None),
+        };
+        self.state = state;
+        action
+    }
+
+    /// Closes the menu after MENU_TIMEOUT without presses, true if it just closed
+    pub fn expire(&mut self, now: u32) -> bool {
+        if self.is_open() && now.wrapping_sub(self.last_press) >= MENU_TIMEOUT {
+            self.state = State::Closed;
+            return true;
+        }
+        false
+    }
+}
+
+pub fn draw(display: &mut Lcd, menu: &Menu, sensors: &Registry, style: Style, backlight: bool, mq7_status: Option<mq7::Status>, uptime: u32) {
+    display.clear().unwrap();
+    match menu.state {
+        State::Closed => {}
+        State::List(selected) => {
+            //five rows, the clock keeps the last one: the selected item and those around it
+            let first = selected.saturating_sub(2).min(ITEMS.len().saturating_sub(5));
+            for (row, item) in ITEMS.iter().enumerate().skip(first).take(5) {
+                let mut text: String<14> = String::new();
+                let _res = write!(text, "{}{}", if row == selected { ">" } else { " " }, item);
+                if row == 1 {
+                    let _res = write!(text, " {}", if backlight { "on" } else { "off" });
+                }
+                display.set_position(0u8, (row - first) as u8).unwrap();
+                display.print(text.as_bytes()).unwrap();
+            }
+        }
+        State::Readings(index) => {
+            if let Some(channel) = sensors.iter().nth(index) {
+                display.print(channel.name.as_bytes()).unwrap();
+                let mut text: String<14> = String::new();
+                let _res = match (channel.reading, channel.error) {
+                    (Some(reading), None) => write!(text, "{}", reading.value.styled(style)),
+                    (Some(reading), Some(_)) => write!(text, "{} stale", reading.value.styled(style)),
+                    (None, _) => write!(text, "--"),
+                };
+                display.set_position(0u8, 2u8).unwrap();
+                display.print(text.as_bytes()).unwrap();
+            }
+        }
+        State::Contrast => {
+            display.print(b"Contrast").unwrap();
+            let mut text: String<14> = String::new();
+            let _res = write!(text, "{}", menu.contrast);
+            display.set_position(0u8, 2u8).unwrap();
+            display.print(text.as_bytes()).unwrap();
+            display.set_position(0u8, 4u8).unwrap();
+            display.print(b"long = save").unwrap();
+        }
+        State::Info => {
+            display.print(b"nucleo-rust").unwrap();
+            display.set_position(0u8, 1u8).unwrap();
+            display.print(FIRMWARE_VERSION.as_bytes()).unwrap();
+            let mut text: String<14> = String::new();
+            let _res = write!(text, "up {}d {:02}:{:02}", uptime / 86400, uptime / 3600 % 24, uptime / 60 % 60);
+            display.set_position(0u8, 2u8).unwrap();
+            display.print(text.as_bytes()).unwrap();
+            if let Some(status) = mq7_status {
+                text.clear();
+                let _res = if status.calibrating > 0 {
+                    write!(text, "MQ7 cal {} left", status.calibrating)
+                } else {
+                    write!(text, "R0 {}", status.r0)
+                };
+                display.set_position(0u8, 3u8).unwrap();
+                display.print(&text.as_bytes()[..text.len().min(14)]).unwrap();
+            }
+        }
+    }
+}
@@ -0,0 +1,445 @@
+use heapless::{Vec, spsc::{Consumer, Producer, Queue}};
+
+use crate::crc::crc16;
+
+/// Longest RTU frame: address, 253 byte PDU, CRC
+pub const MAX_FRAME: usize = 256;
+/// heapless queues keep one slot free, so one request can wait while another is handled
+const QUEUE_SIZE: usize = 3;
+
+pub type Frame = Vec<u8, MAX_FRAME>;
+pub type FrameQueue = Queue<Frame, QUEUE_SIZE>;
+pub type FrameProducer = Producer<'static, Frame, QUEUE_SIZE>;
+pub type FrameConsumer = Consumer<'static, Frame, QUEUE_SIZE>;
+
+const BROADCAST: u8 = 0;
+const READ_COILS: u8 = 0x01;
+const READ_HOLDING_REGISTERS: u8 = 0x03;
+const READ_INPUT_REGISTERS: u8 = 0x04;
+const WRITE_SINGLE_COIL: u8 = 0x05;
+const WRITE_SINGLE_REGISTER: u8 = 0x06;
+const WRITE_MULTIPLE_COILS: u8 = 0x0F;
+const WRITE_MULTIPLE_REGISTERS: u8 = 0x10;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Exception {
+    IllegalFunction = 1,
+    IllegalDataAddress = 2,
+    IllegalDataValue = 3,
+}
+
+/// Data model the slave serves, addresses are 0-based PDU addresses
+pub trait Registers {
+    fn coil(&self, address: u16) -> Result<bool, Exception>;
+    fn set_coil(&mut self, address: u16, on: bool) -> Result<(), Exception>;
+    fn input_register(&self, address: u16) -> Result<u16, Exception>;
+    fn holding_register(&self, address: u16) -> Result<u16, Exception>;
+    fn set_holding_register(&mut self, address: u16, value: u16) -> Result<(), Exception>;
+}
+
+/// Collects received bytes into a frame, the USART idle-line interrupt ends it.
+/// An idle line is one character of silence, stricter than the 3.5 the RTU spec asks for,
+/// masters send each frame back to back so it only splits frames from a stalling master
+pub struct Receiver {
+    frame: Frame,
+    /// more than MAX_FRAME bytes came in, the frame is dropped
+    overrun: bool,
+}
+
+impl Receiver {
+    pub const fn new() -> Receiver {
+        Receiver { frame: Vec::new(), overrun: false }
+    }
+
+    pub fn push(&mut self, byte: u8) {
+        if self.frame.push(byte).is_err() {
+            self.overrun = true;
+        }
+    }
+
+    /// Silence on the line: the frame received so far, None if empty or overrun
+    pub fn end(&mut self) -> Option<Frame> {
+        let frame = core::mem::replace(&mut self.frame, Vec::new());
+        if core::mem::replace(&mut self.overrun, false) || frame.is_empty() {
+            return None;
+        }
+        Some(frame)
+    }
+}
+
+impl Default for Receiver {
+    fn default() -> Receiver {
+        Receiver::new()
+    }
+}
+
+/// Handles a request frame for slave `address`, returns the response to send.
+/// Frames with a bad CRC or for another slave get no response, neither do broadcasts
+pub fn handle(request: &[u8], address: u8, registers: &mut dyn Registers) -> Option<Frame> {
+    if request.len() < 4 {
+        return None;
+    }
+    let (body, crc) = request.split_at(request.len() - 2);
+    if crc16(body) != u16::from_le_bytes([crc[0], crc[1]]) {
+        return None;
+    }
+    let slave = body[0];
+    if slave != address && slave != BROADCAST {
+        return None;
+    }
+    let function = body[1];
+    let mut response = Frame::new();
+    let _res = response.extend_from_slice(&[address, function]);
+    if let Err(exception) = execute(function, &body[2..], registers, &mut response) {
+        response.truncate(1);
+        let _res = response.extend_from_slice(&[function | 0x80, exception as u8]);
+    }
+    if slave == BROADCAST {
+        return None;
+    }
+    let crc = crc16(&response);
+    let _res = response.extend_from_slice(&crc.to_le_bytes());
+    Some(response)
+}
+
+/// Runs one PDU, appends the response data after the address and function code
+fn execute(function: u8, pdu: &[u8], registers: &mut dyn Registers, response: &mut Frame) -> Result<(), Exception> {
+    let word = |i: usize| -> Result<u16, Exception> {
+        match pdu.get(i..i + 2) {
+            Some(bytes) => Ok(u16::from_be_bytes([bytes[0], bytes[1]])),
+            None => Err(Exception::IllegalDataValue),
+        }
+    };
+    match function {
+        READ_COILS => {
+            let (start, count) = (word(0)?, word(2)?);
+            if !(1..=2000).contains(&count) {
+                return Err(Exception::IllegalDataValue);
+            }
+            let bytes = (count as usize).div_ceil(8);
+            push(response, &[bytes as u8])?;
+            let mut packed = [0u8; 250];
+            for i in 0..count {
+                if registers.coil(address(start, i)?)? {
+                    packed[i as usize / 8] |= 1 << (i % 8);
+                }
+            }
+            push(response, &packed[..bytes])
+        }
+        READ_HOLDING_REGISTERS | READ_INPUT_REGISTERS => {
+            let (start, count) = (word(0)?, word(2)?);
+            if !(1..=125).contains(&count) {
+                return Err(Exception::IllegalDataValue);
+            }
+            push(response, &[count as u8 * 2])?;
+            for i in 0..count {
+                let value = if function == READ_INPUT_REGISTERS {
+                    registers.input_register(address(start, i)?)?
+                } else {
+                    registers.holding_register(address(start, i)?)?
+                };
+                push(response, &value.to_be_bytes())?;
+            }
+            Ok(())
+        }
+        WRITE_SINGLE_COIL => {
+            let on = match word(2)? {
+                0xFF00 => true,
+                0x0000 => false,
+                _ => return Err(Exception::IllegalDataValue),
+            };
+            registers.set_coil(word(0)?, on)?;
+            push(response, &pdu[..4])
+        }
+        WRITE_SINGLE_REGISTER => {
+            registers.set_holding_register(word(0)?, word(2)?)?;
+            push(response, &pdu[..4])
+        }
+        WRITE_MULTIPLE_COILS => {
+            let (start, count) = (word(0)?, word(2)?);
+            let bytes = *pdu.get(4).ok_or(Exception::IllegalDataValue)? as usize;
+            if !(1..=1968).contains(&count) || bytes != (count as usize).div_ceil(8) || pdu.len() != 5 + bytes {
+                return Err(Exception::IllegalDataValue);
+            }
+            //the whole range has to exist before anything is written
+            address(start, count - 1)?;
+            registers.coil(start + count - 1)?;
+            for i in 0..count {
+                let on = pdu[5 + i as usize / 8] & (1 << (i % 8)) != 0;
+                registers.set_coil(start + i, on)?;
+            }
+            push(response, &pdu[..4])
+        }
+        WRITE_MULTIPLE_REGISTERS => {
+            let (start, count) = (word(0)?, word(2)?);
+            let bytes = *pdu.get(4).ok_or(Exception::IllegalDataValue)? as usize;
+            if !(1..=123).contains(&count) || bytes != count as usize * 2 || pdu.len() != 5 + bytes {
+                return Err(Exception::IllegalDataValue);
+            }
+            address(start, count - 1)?;
+            registers.holding_register(start + count - 1)?;
+            for i in 0..count {
+                registers.set_holding_register(start + i, word(5 + i as usize * 2)?)?;
+            }
+            push(response, &pdu[..4])
+        }
+        _ => Err(Exception::IllegalFunction),
+    }
+}
+
+/// `offset`th address after `start`, the address space ends at 0xFFFF
+fn address(start: u16, offset: u16) -> Result<u16, Exception> {
+    start.checked_add(offset).ok_or(Exception::IllegalDataAddress)
+}
+
+fn push(response: &mut Frame, bytes: &[u8]) -> Result<(), Exception> {
+    response.extend_from_slice(bytes).map_err(|_| Exception::IllegalDataValue)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SLAVE: u8 = 17;
+    const SIZE: u16 = 8;
+    /// The mock holding register that only takes values up to LIMIT, like a config field
+    const LIMITED: u16 = 7;
+    const LIMIT: u16 = 1000;
+
+    /// Eight coils, input and holding registers, anything past them is an illegal address
+    struct Map {
+        coils: [bool; SIZE as usize],
+        inputs: [u16; SIZE as usize],
+        holding: [u16; SIZE as usize],
+    }
+
+    impl Map {
+        fn new() -> Map {
+            Map { coils: [false; SIZE as usize], inputs: [0x1100, 0x2201, 0, 0, 0, 0, 0, 0xFFFF], holding: [0; SIZE as usize] }
+        }
+    }
+
+    fn index(address: u16) -> Result<usize, Exception> {
+        if address < SIZE { Ok(address as usize) } else { Err(Exception::IllegalDataAddress) }
+    }
+
+    impl Registers for Map {
+        fn coil(&self, address: u16) -> Result<bool, Exception> {
+            Ok(self.coils[index(address)?])
+        }
+
+        fn set_coil(&mut self, address: u16, on: bool) -> Result<(), Exception> {
+            self.coils[index(address)?] = on;
+            Ok(())
+        }
+
+        fn input_register(&self, address: u16) -> Result<u16, Exception> {
+            Ok(self.inputs[index(address)?])
+        }
+
+        fn holding_register(&self, address: u16) -> Result<u16, Exception> {
+            Ok(self.holding[index(address)?])
+        }
+
+        fn set_holding_register(&mut self, address: u16, value: u16) -> Result<(), Exception> {
+            let i = index(address)?;
+            if address == LIMITED && value > LIMIT {
+                return Err(Exception::IllegalDataValue);
+            }
+            self.holding[i] = value;
+            Ok(())
+        }
+    }
+
+    /// `body` with its CRC appended
+    fn frame(body: &[u8]) -> Frame {
+        let mut frame = Frame::from_slice(body).unwrap();
+        frame.extend_from_slice(&crc16(body).to_le_bytes()).unwrap();
+        frame
+    }
+
+    /// Response to `body` sent to SLAVE, without its CRC once that is checked
+    fn request(map: &mut Map, body: &[u8]) -> Option<Frame> {
+        let mut request = Frame::from_slice(&[SLAVE]).unwrap();
+        request.extend_from_slice(body).unwrap();
+        let mut response = handle(&frame(&request), SLAVE, map)?;
+        let (data, crc) = response.split_at(response.len() - 2);
+        assert_eq!(crc16(data).to_le_bytes(), crc);
+        response.truncate(response.len() - 2);
+        Some(response)
+    }
+
+    fn exception(function: u8, exception: Exception) -> Option<Frame> {
+        Some(Frame::from_slice(&[SLAVE, function | 0x80, exception as u8]).unwrap())
+    }
+
+    fn reply(bytes: &[u8]) -> Option<Frame> {
+        Some(Frame::from_slice(bytes).unwrap())
+    }
+
+    #[test]
+    fn crc_of_a_known_frame() {
+        //read 2 holding registers from 0x006B of slave 17, the spec's example frame
+        assert_eq!(crc16(&[0x11, 0x03, 0x00, 0x6B, 0x00, 0x03]), 0x8776);
+    }
+
+    #[test]
+    fn read_coils() {
+        let mut map = Map::new();
+        map.coils = [true, false, true, true, false, false, false, true];
+        assert_eq!(request(&mut map, &[0x01, 0, 0, 0, 8]), reply(&[SLAVE, 0x01, 1, 0b1000_1101]));
+        assert_eq!(request(&mut map, &[0x01, 0, 2, 0, 3]), reply(&[SLAVE, 0x01, 1, 0b011]));
+        assert_eq!(request(&mut map, &[0x01, 0, 7, 0, 2]), exception(0x01, Exception::IllegalDataAddress));
+        assert_eq!(request(&mut map, &[0x01, 0, 0, 0, 0]), exception(0x01, Exception::IllegalDataValue));
+    }
+
+    #[test]
+    fn read_registers() {
+        let mut map = Map::new();
+        map.holding[1] = 0x0102;
+        map.holding[2] = 0xA0B0;
+        assert_eq!(request(&mut map, &[0x03, 0, 1, 0, 2]), reply(&[SLAVE, 0x03, 4, 0x01, 0x02, 0xA0, 0xB0]));
+        assert_eq!(request(&mut map, &[0x04, 0, 0, 0, 2]), reply(&[SLAVE, 0x04, 4, 0x11, 0x00, 0x22, 0x01]));
+        assert_eq!(request(&mut map, &[0x04, 0, 7, 0, 1]), reply(&[SLAVE, 0x04, 2, 0xFF, 0xFF]));
+        assert_eq!(request(&mut map, &[0x03, 0, 6, 0, 3]), exception(0x03, Exception::IllegalDataAddress));
+        assert_eq!(request(&mut map, &[0x04, 0, 0, 0, 126]), exception(0x04, Exception::IllegalDataValue));
+        //count missing
+        assert_eq!(request(&mut map, &[0x03, 0, 0]), exception(0x03, Exception::IllegalDataValue));
+    }
+
+    #[test]
+    fn write_single() {
+        let mut map = Map::new();
+        assert_eq!(request(&mut map, &[0x05, 0, 3, 0xFF, 0x00]), reply(&[SLAVE, 0x05, 0, 3, 0xFF, 0x00]));
+        assert!(map.coils[3]);
+        assert_eq!(request(&mut map, &[0x05, 0, 3, 0x00, 0x00]), reply(&[SLAVE, 0x05, 0, 3, 0x00, 0x00]));
+        assert!(!map.coils[3]);
+        assert_eq!(request(&mut map, &[0x05, 0, 3, 0x00, 0x01]), exception(0x05, Exception::IllegalDataValue));
+        assert_eq!(request(&mut map, &[0x05, 0, 8, 0xFF, 0x00]), exception(0x05, Exception::IllegalDataAddress));
+
+        assert_eq!(request(&mut map, &[0x06, 0, 2, 0x12, 0x34]), reply(&[SLAVE, 0x06, 0, 2, 0x12, 0x34]));
+        assert_eq!(map.holding[2], 0x1234);
+        assert_eq!(request(&mut map, &[0x06, 0, 8, 0, 1]), exception(0x06, Exception::IllegalDataAddress));
+        //the register map refuses the value
+        assert_eq!(request(&mut map, &[0x06, 0, LIMITED as u8, 0x03, 0xE9]), exception(0x06, Exception::IllegalDataValue));
+        assert_eq!(map.holding[LIMITED as usize], 0);
+    }
+
+    #[test]
+    fn write_multiple() {
+        let mut map = Map::new();
+        assert_eq!(request(&mut map, &[0x0F, 0, 1, 0, 7, 1, 0b0110_0101]), reply(&[SLAVE, 0x0F, 0, 1, 0, 7]));
+        assert_eq!(map.coils, [false, true, false, true, false, false, true, true]);
+        //data byte count not matching the coil count
+        assert_eq!(request(&mut map, &[0x0F, 0, 0, 0, 9, 1, 0xFF]), exception(0x0F, Exception::IllegalDataValue));
+
+        assert_eq!(request(&mut map, &[0x10, 0, 5, 0, 2, 4, 0x00, 0x0A, 0x01, 0x00]), reply(&[SLAVE, 0x10, 0, 5, 0, 2]));
+        assert_eq!(map.holding[5..7], [0x000A, 0x0100]);
+        assert_eq!(request(&mut map, &[0x10, 0, 0, 0, 1, 4, 0, 1, 0, 2]), exception(0x10, Exception::IllegalDataValue));
+        assert_eq!(request(&mut map, &[0x10, 0, 0, 0, 0, 0]), exception(0x10, Exception::IllegalDataValue));
+    }
+
+    #[test]
+    fn write_multiple_past_the_map() {
+        let mut map = Map::new();
+        //the last of the range is missing, nothing is written
+        assert_eq!(request(&mut map, &[0x0F, 0, 4, 0, 5, 1, 0x1F]), exception(0x0F, Exception::IllegalDataAddress));
+        assert_eq!(map.coils, [false; SIZE as usize]);
+        assert_eq!(request(&mut map, &[0x10, 0, 6, 0, 3, 6, 0, 1, 0, 2, 0, 3]), exception(0x10, Exception::IllegalDataAddress));
+        assert_eq!(map.holding, [0; SIZE as usize]);
+        //a range past 0xFFFF
+        assert_eq!(request(&mut map, &[0x10, 0xFF, 0xFF, 0, 2, 4, 0, 1, 0, 2]), exception(0x10, Exception::IllegalDataAddress));
+        assert_eq!(request(&mut map, &[0x01, 0xFF, 0xFF, 0, 2]), exception(0x01, Exception::IllegalDataAddress));
+    }
+
+    #[test]
+    fn illegal_function() {
+        let mut map = Map::new();
+        for function in [0x02, 0x07, 0x2B, 0x7F] {
+            assert_eq!(request(&mut map, &[function, 0, 0, 0, 1]), exception(function, Exception::IllegalFunction));
+        }
+    }
+
+    #[test]
+    fn ignored_frames() {
+        let mut map = Map::new();
+        let mut bad_crc = frame(&[SLAVE, 0x06, 0, 0, 0, 1]);
+        bad_crc[6] ^= 1;
+        assert_eq!(handle(&bad_crc, SLAVE, &mut map), None);
+        assert_eq!(handle(&frame(&[SLAVE + 1, 0x06, 0, 0, 0, 1]), SLAVE, &mut map), None);
+        assert_eq!(handle(&frame(&[SLAVE]), SLAVE, &mut map), None);
+        assert_eq!(handle(&[], SLAVE, &mut map), None);
+        assert_eq!(map.holding, [0; SIZE as usize]);
+    }
+
+    #[test]
+    fn broadcast() {
+        let mut map = Map::new();
+        //written without a reply
+        assert_eq!(handle(&frame(&[BROADCAST, 0x06, 0, 0, 0, 1]), SLAVE, &mut map), None);
+        assert_eq!(handle(&frame(&[BROADCAST, 0x05, 0, 1, 0xFF, 0x00]), SLAVE, &mut map), None);
+        assert_eq!(map.holding[0], 1);
+        assert!(map.coils[1]);
+        //errors stay silent too
+        assert_eq!(handle(&frame(&[BROADCAST, 0x06, 0, 8, 0, 1]), SLAVE, &mut map), None);
+        assert_eq!(handle(&frame(&[BROADCAST, 0x2B, 0, 0]), SLAVE, &mut map), None);
+    }
+
+    /// Frames as a master puts them on the line, CRC included, through the receiver and back
+    fn exchange(map: &mut Map, line: &[u8]) -> Option<Frame> {
+        let mut receiver = Receiver::new();
+        for &byte in line {
+            receiver.push(byte);
+        }
+        handle(&receiver.end()?, SLAVE, map)
+    }
+
+    #[test]
+    fn master_frames() {
+        let mut map = Map::new();
+        map.holding[..2].copy_from_slice(&[0x000A, 0x0102]);
+        let exchanges: [(&[u8], Option<&[u8]>); 9] = [
+            //read holding registers 0..2
+            (&[0x11, 0x03, 0x00, 0x00, 0x00, 0x02, 0xC6, 0x9B], Some(&[0x11, 0x03, 0x04, 0x00, 0x0A, 0x01, 0x02, 0x4B, 0xA1])),
+            //read input registers 0..2
+            (&[0x11, 0x04, 0x00, 0x00, 0x00, 0x02, 0x73, 0x5B], Some(&[0x11, 0x04, 0x04, 0x11, 0x00, 0x22, 0x01, 0x36, 0x19])),
+            //write 0x1234 to register 2, echoed
+            (&[0x11, 0x06, 0x00, 0x02, 0x12, 0x34, 0x27, 0xED], Some(&[0x11, 0x06, 0x00, 0x02, 0x12, 0x34, 0x27, 0xED])),
+            //write 1, 2 to registers 3..5
+            (&[0x11, 0x10, 0x00, 0x03, 0x00, 0x02, 0x04, 0x00, 0x01, 0x00, 0x02, 0x37, 0x7B], Some(&[0x11, 0x10, 0x00, 0x03, 0x00, 0x02, 0xB3, 0x58])),
+            //the first read with its last CRC byte damaged, and sent to slave 18
+            (&[0x11, 0x03, 0x00, 0x00, 0x00, 0x02, 0xC6, 0x9A], None),
+            (&[0x12, 0x03, 0x00, 0x00, 0x00, 0x02, 0xC6, 0xA8], None),
+            //read device identification isn't served: illegal function
+            (&[0x11, 0x2B, 0x0E, 0x01, 0x00, 0xB1, 0xB4], Some(&[0x11, 0xAB, 0x01, 0x9F, 0x35])),
+            //register 8 past the map: illegal data address
+            (&[0x11, 0x03, 0x00, 0x08, 0x00, 0x01, 0x07, 0x58], Some(&[0x11, 0x83, 0x02, 0xC1, 0x34])),
+            //1001 refused by the map: illegal data value
+            (&[0x11, 0x06, 0x00, 0x07, 0x03, 0xE9, 0xFB, 0xE5], Some(&[0x11, 0x86, 0x03, 0x03, 0xA4])),
+        ];
+        for (request, response) in exchanges {
+            assert_eq!(exchange(&mut map, request).as_deref(), response, "request {:02X?}", request);
+        }
+        assert_eq!(map.holding[..6], [0x000A, 0x0102, 0x1234, 0x0001, 0x0002, 0]);
+        //registers 6..9 overrun the map, nothing is written
+        let overrun = [0x11, 0x10, 0x00, 0x06, 0x00, 0x03, 0x06, 0x00, 0x01, 0x00, 0x02, 0x00, 0x03, 0xE4, 0x0E];
+        assert_eq!(exchange(&mut map, &overrun).as_deref(), Some(&[0x11, 0x90, 0x02, 0xCC, 0x04][..]));
+        assert_eq!(map.holding[6..], [0, 0]);
+    }
+
+    #[test]
+    fn receiver() {
+        let mut receiver = Receiver::new();
+        assert_eq!(receiver.end(), None);
+        for byte in [1, 2, 3] {
+            receiver.push(byte);
+        }
+        assert_eq!(receiver.end(), reply(&[1, 2, 3]));
+        for _ in 0..=MAX_FRAME {
+            receiver.push(0);
+        }
+        assert_eq!(receiver.end(), None);
+        receiver.push(4);
+        assert_eq!(receiver.end(), reply(&[4]));
+    }
+}
@@ -0,0 +1,75 @@
+use stm32f1xx_hal::pac::{EXTI, RCC};
+
+/// Seconds without UART activity before the low-power profile blanks the display and starts using STOP mode
+pub const IDLE_TIMEOUT: u32 = 30;
+/// Longest STOP period, the IWDG keeps running in STOP and has to be fed in between
+pub const MAX_SLEEP: u32 = 10;
+
+pub struct Power {
+    pub low_power: bool,
+    /// uptime of the last received command or UART wakeup
+    pub last_activity: u32,
+    /// display and backlight switched off for inactivity
+    pub display_off: bool,
+    /// the next wait for the RTC alarm may use STOP mode
+    pub stop: bool,
+}
+
+impl Power {
+    pub const fn new(low_power: bool) -> Power {
+        Power { low_power, last_activity: 0, display_off: false, stop: false }
+    }
+
+    /// true once the low-power profile has seen no activity for IDLE_TIMEOUT
+    pub fn is_idle(&self, now: u32) -> bool {
+        self.low_power && now.wrapping_sub(self.last_activity) >= IDLE_TIMEOUT
+    }
+
+    /// Seconds until the next tick: every second while awake, up to the sample interval when idle
+    pub fn tick_period(&self, now: u32, sample_interval: u16) -> u32 {
+        if self.is_idle(now) {
+            (sample_interval as u32).clamp(1, MAX_SLEEP)
+        } else {
+            1
+        }
+    }
+}
+
+/// Routes the RTC alarm (EXTI17) and a falling edge on USART2 RX (PA3, EXTI3) to EXTI, so both can end STOP mode;
+/// PA3 is already the EXTI3 source after reset
+pub fn configure_wakeup(exti: &EXTI) {
+    exti.rtsr.modify(|_, w| w.tr17().set_bit());
+    exti.ftsr.modify(|_, w| w.tr3().set_bit());
+    exti.imr.modify(|_, w| w.mr17().set_bit());
+}
+
+/// RX wakeup is only armed while sleeping, otherwise every received start bit would interrupt
+pub fn listen_rx_wakeup(exti: &EXTI, listen: bool) {
+    exti.pr.write(|w| w.pr3().set_bit());
+    exti.imr.modify(|_, w| w.mr3().bit(listen));
+}
+
+pub fn clear_button_wakeup(exti: &EXTI) {
+    exti.pr.write(|w| w.pr13().set_bit());
+}
+
+pub fn clear_rtc_alarm(exti: &EXTI) {
+    exti.pr.write(|w| w.pr17().set_bit());
+}
+
+/// STOP mode exits on HSI, brings HSE and the PLL back for the 72 MHz configuration;
+/// the PLL multiplier and bus prescalers survive STOP
+#[allow(unsafe_code)]
+pub fn restore_clocks() {
+    //RCC belongs to the HAL after constrain(), only the clock enable and switch bits are touched here
+    let rcc = unsafe { &*RCC::ptr() };
+    if rcc.cfgr.read().sws().is_pll() {
+        return;
+    }
+    rcc.cr.modify(|_, w| w.hseon().set_bit());
+    while rcc.cr.read().hserdy().bit_is_clear() {}
+    rcc.cr.modify(|_, w| w.pllon().set_bit());
+    while rcc.cr.read().pllrdy().bit_is_clear() {}
+    rcc.cfgr.modify(|_, w| w.sw().pll());
+    while !rcc.cfgr.read().sws().is_pll() {}
+}
@@ -0,0 +1,181 @@
+use heapless::Vec;
+use stm32f1xx_hal::{
+    adc::{Adc, AdcDma, ChannelTimeSequence, SampleTime, Scan, SetChannels},
+    dma::{dma1::C1, ReadDma},
+    gpio::{Analog, Pin},
+    pac::ADC1,
+    timer::SysDelay,
+};
+
+use super::{Sensor, SensorError, Value, MAX_VALUES};
+
+pub const SCAN_LENGTH: usize = 5;
+/// Regular sequence of a scan: A0, A1, A2 (PA4), internal temperature sensor, VREFINT
+const SEQUENCE: [u8; SCAN_LENGTH] = [0, 1, 4, 16, 17];
+/// Positions in SEQUENCE
+pub const MQ7: usize = 0;
+const SPARE: [usize; 2] = [1, 2];
+const MCU_TEMPERATURE: usize = 3;
+const VREFINT: usize = 4;
+/// Most scans averaged into one reading, the sums stay far below u32::MAX
+pub const MAX_OVERSAMPLE: u8 = 64;
+const ADC_MAX: u32 = 4095;
+/// VDDA the spare inputs are corrected to
+const NOMINAL_MV: u32 = 3300;
+/// VREFINT typical voltage, the F103 has no factory calibration of it
+const VREFINT_MV: u32 = 1200;
+/// Temperature sensor voltage at 25 °C and slope, typical datasheet values
+const V25_UV: i32 = 1_430_000;
+const SLOPE_UV: i32 = 4300;
+
+/// DMA target of one scan
+pub type ScanBuffer = [u16; SCAN_LENGTH];
+
+/// External inputs of the scan: MQ-7 on A0, spare inputs on A1 and A2
+pub struct AnalogPins(pub Pin<'A', 0, Analog>, pub Pin<'A', 1, Analog>, pub Pin<'A', 4, Analog>);
+
+impl SetChannels<AnalogPins> for Adc<ADC1> {
+    fn set_samples(&mut self) {
+        //the temperature sensor needs 17 us and the MQ-7 divider is high impedance, so the longest sample time for all
+        for channel in SEQUENCE {
+            self.set_channel_sample_time(channel, SampleTime::T_239);
+        }
+    }
+
+    fn set_sequence(&mut self) {
+        self.set_regular_sequence(&SEQUENCE);
+        self.set_continuous_mode(false);
+        self.set_discontinuous_mode(None);
+    }
+}
+
+/// Linear correction of a spare input after the VDDA correction: counts * gain / 1000 + offset, clamped to 0..=u16::MAX
+#[derive(Copy, Clone)]
+pub struct Calibration {
+    pub gain: u16,
+    pub offset: i16,
+}
+
+impl Calibration {
+    pub const NONE: Calibration = Calibration { gain: 1000, offset: 0 };
+
+    fn apply(&self, counts: u16) -> u16 {
+        let value = counts as i32 * self.gain as i32 / 1000 + self.offset as i32;
+        value.clamp(0, u16::MAX as i32) as u16
+    }
+}
+
+/// ADC1 in scan mode, every conversion of a scan is moved to memory by DMA1 channel 1.
+/// VREFINT gives the actual VDDA, which the spare inputs are corrected for and the MQ-7 gets with its counts
+pub struct AnalogSensor {
+    /// both are only None while a transfer runs
+    dma: Option<AdcDma<ADC1, AnalogPins, Scan, C1>>,
+    buffer: Option<&'static mut ScanBuffer>,
+    /// averaged counts of the last scan
+    counts: ScanBuffer,
+    /// scans averaged per reading, 1..=MAX_OVERSAMPLE
+    oversample: u8,
+    calibration: [Calibration; 2],
+    /// a scan ran since the last sample
+    fresh: bool,
+}
+
+impl AnalogSensor {
+    pub fn new(adc: Adc<ADC1>, pins: AnalogPins, channel: C1, buffer: &'static mut ScanBuffer) -> AnalogSensor {
+        let dma = adc.with_scan_dma(pins, channel);
+        enable_internal_channels();
+        AnalogSensor {
+            dma: Some(dma),
+            buffer: Some(buffer),
+            counts: [0; SCAN_LENGTH],
+            oversample: 1,
+            calibration: [Calibration::NONE; 2],
+            fresh: false,
+        }
+    }
+
+    pub fn set_oversample(&mut self, oversample: u8) {
+        self.oversample = oversample.clamp(1, MAX_OVERSAMPLE);
+    }
+
+    pub fn set_calibration(&mut self, calibration: [Calibration; 2]) {
+        self.calibration = calibration;
+    }
+
+    /// Runs `oversample` scans and keeps their rounded average, a scan takes about 100 us
+    pub fn scan(&mut self) {
+        let (mut dma, mut buffer) = match (self.dma.take(), self.buffer.take()) {
+            (Some(dma), Some(buffer)) => (dma, buffer),
+            _ => return,
+        };
+        let mut sums = [0u32; SCAN_LENGTH];
+        for _ in 0..self.oversample {
+            let (done, adc) = dma.read(buffer).wait();
+            for (sum, &counts) in sums.iter_mut().zip(done.iter()) {
+                *sum += counts as u32;
+            }
+            buffer = done;
+            dma = adc;
+        }
+        let n = self.oversample as u32;
+        for (counts, sum) in self.counts.iter_mut().zip(sums) {
+            *counts = ((sum + n / 2) / n) as u16;
+        }
+        self.dma = Some(dma);
+        self.buffer = Some(buffer);
+        self.fresh = true;
+    }
+
+    /// Averaged counts of a SEQUENCE position from the last scan
+    pub fn counts(&self, position: usize) -> u16 {
+        self.counts[position]
+    }
+
+    /// Analog supply in mV, the ADC reference; nominal until the first scan
+    pub fn vdda(&self) -> u16 {
+        match self.counts[VREFINT] as u32 {
+            0 => NOMINAL_MV as u16,
+            vrefint => (VREFINT_MV * ADC_MAX / vrefint).min(u16::MAX as u32) as u16,
+        }
+    }
+
+    /// Die temperature in centi-degrees, +-1.5 °C at best with the typical sensor values
+    fn mcu_temperature(&self) -> i16 {
+        let sense = (self.counts[MCU_TEMPERATURE] as u64 * self.vdda() as u64 * 1000 / ADC_MAX as u64) as i32;
+        ((V25_UV - sense) * 100 / SLOPE_UV + 2500) as i16
+    }
+
+    /// Counts of a SEQUENCE position as they would read with a nominal VDDA
+    fn ratiometric(&self, position: usize) -> u16 {
+        (self.counts[position] as u32 * self.vdda() as u32 / NOMINAL_MV).min(ADC_MAX) as u16
+    }
+}
+
+/// The HAL powers the temperature sensor and VREFINT only around its one-shot reads;
+/// they need 10 us to settle, long passed by the first scan
+#[allow(unsafe_code)]
+fn enable_internal_channels() {
+    //ADC1 belongs to the AdcDma, only the TSVREFE bit is touched here
+    let adc = unsafe { &*ADC1::ptr() };
+    adc.cr2.modify(|_, w| w.tsvrefe().set_bit());
+}
+
+impl Sensor for AnalogSensor {
+    fn channels(&self) -> &'static [(u8, &'static str)] {
+        &[(b'1', "Analog A1"), (b'2', "Analog A2"), (b'm', "MCU temp"), (b'v', "Supply")]
+    }
+
+    /// Reports the last scan, the tick task runs the scans
+    fn sample(&mut self, _delay: &mut SysDelay, _now: u32) -> nb::Result<Vec<Value, MAX_VALUES>, SensorError> {
+        if !core::mem::replace(&mut self.fresh, false) {
+            return Err(nb::Error::WouldBlock);
+        }
+        let mut values = Vec::new();
+        for (position, calibration) in SPARE.iter().zip(self.calibration.iter()) {
+            values.push(Value::Raw(calibration.apply(self.ratiometric(*position)))).ok();
+        }
+        values.push(Value::Temperature(self.mcu_temperature())).ok();
+        values.push(Value::Voltage(self.vdda())).ok();
+        Ok(values)
+    }
+}
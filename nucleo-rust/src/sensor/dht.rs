@@ -0,0 +1,214 @@
+use embedded_hal::{
+    blocking::delay::{DelayMs, DelayUs},
+    digital::v2::{InputPin, OutputPin},
+};
+use heapless::Vec;
+use stm32f1xx_hal::timer::SysDelay;
+
+use super::{Sensor, SensorError, Value, MAX_VALUES};
+
+/// Failed reads retried before the channels are marked stale, each after twice the previous wait
+const MAX_RETRIES: u8 = 3;
+/// Polls of 1 us waiting for a level change, generous since the delay itself takes longer than 1 us
+const LEVEL_TIMEOUT: u32 = 200;
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum Model {
+    /// 1 °C / 1 % resolution, 0..50 °C
+    Dht11,
+    /// DHT22 / AM2302, 0.1 °C / 0.1 % resolution, -40..80 °C
+    Dht22,
+}
+
+impl Model {
+    /// Seconds the sensor needs between two reads
+    fn min_interval(&self) -> u32 {
+        match self {
+            Model::Dht11 => 1,
+            Model::Dht22 => 2,
+        }
+    }
+
+    /// Host start signal: how long the line is pulled low
+    fn start_ms(&self) -> u8 {
+        match self {
+            Model::Dht11 => 18,
+            Model::Dht22 => 2,
+        }
+    }
+
+    /// (temperature, humidity) in hundredths from the 4 data bytes, wide enough for any frame
+    /// so a garbage one can't overflow before is_plausible rejects it
+    fn decode(&self, data: &[u8; 5]) -> (i32, u32) {
+        match self {
+            //integral and decimal bytes, the sign is in the temperature decimal byte
+            Model::Dht11 => {
+                let temperature = data[2] as i32 * 100 + (data[3] & 0x7f) as i32 * 10;
+                let temperature = if data[3] & 0x80 != 0 { -temperature } else { temperature };
+                (temperature, data[0] as u32 * 100 + data[1] as u32 * 10)
+            }
+            //tenths as big-endian u16, sign and magnitude for the temperature
+            Model::Dht22 => {
+                let temperature = u16::from_be_bytes([data[2] & 0x7f, data[3]]) as i32 * 10;
+                let temperature = if data[2] & 0x80 != 0 { -temperature } else { temperature };
+                (temperature, u16::from_be_bytes([data[0], data[1]]) as u32 * 10)
+            }
+        }
+    }
+
+    /// Rejects values outside what the sensor can measure, the usual result of a misread bit
+    fn is_plausible(&self, temperature: i32, humidity: u32) -> bool {
+        let temperatures = match self {
+            Model::Dht11 => -2000..=6000,
+            Model::Dht22 => -4000..=8000,
+        };
+        temperatures.contains(&temperature) && humidity <= 10000
+    }
+}
+
+/// DHT11 or DHT22 humidity & temperature sensor on a single open-drain pin
+pub struct DhtSensor<P> {
+    pin: P,
+    model: Model,
+    /// calibration offset added to temperatures, centi-degrees
+    offset: i16,
+    /// uptime of the last read attempt
+    last_read: Option<u32>,
+    /// consecutive failed reads
+    failures: u8,
+    /// last plausible readings, newest first, for the median filter
+    window: [(i16, u16); 3],
+    /// readings in `window`
+    count: usize,
+}
+
+impl<P, E> DhtSensor<P>
+where
+    P: InputPin<Error = E> + OutputPin<Error = E>,
+{
+    pub fn new(pin: P, model: Model) -> DhtSensor<P> {
+        DhtSensor { pin, model, offset: 0, last_read: None, failures: 0, window: [(0, 0); 3], count: 0 }
+    }
+
+    pub fn set_offset(&mut self, offset: i16) {
+        self.offset = offset;
+    }
+
+    /// Switching models drops the filter window, the old readings had another resolution
+    pub fn set_model(&mut self, model: Model) {
+        if model != self.model {
+            self.model = model;
+            self.count = 0;
+        }
+    }
+
+    /// true when a failed read is waiting for its retry at `now`
+    pub fn retry_due(&self, now: u32) -> bool {
+        self.failures > 0 && self.failures <= MAX_RETRIES && self.ready(now)
+    }
+
+    /// Minimum sampling interval passed, doubled for every failed read after the first
+    fn ready(&self, now: u32) -> bool {
+        let wait = self.model.min_interval() << self.failures.saturating_sub(1).min(MAX_RETRIES);
+        self.last_read.is_none_or(|last| now.wrapping_sub(last) >= wait)
+    }
+
+    fn read(&mut self, delay: &mut SysDelay) -> Result<[u8; 5], SensorError> {
+        self.pin.set_low().map_err(|_| SensorError::Bus)?;
+        delay.delay_ms(self.model.start_ms());
+        self.pin.set_high().map_err(|_| SensorError::Bus)?;
+        delay.delay_us(40u8);
+
+        //response: 80 us low, 80 us high, then the first bit's low period
+        self.wait_for(false, delay)?;
+        self.wait_for(true, delay)?;
+        self.wait_for(false, delay)?;
+
+        //each bit is 50 us low, then 26-28 us high for a 0 or 70 us high for a 1
+        let mut data = [0u8; 5];
+        for bit in 0..40 {
+            self.wait_for(true, delay)?;
+            delay.delay_us(35u8);
+            if self.pin.is_high().map_err(|_| SensorError::Bus)? {
+                data[bit / 8] |= 0x80 >> (bit % 8);
+                self.wait_for(false, delay)?;
+            }
+        }
+
+        let sum = data[..4].iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+        if sum != data[4] {
+            return Err(SensorError::Checksum);
+        }
+        Ok(data)
+    }
+
+    fn wait_for(&mut self, high: bool, delay: &mut SysDelay) -> Result<(), SensorError> {
+        for _ in 0..LEVEL_TIMEOUT {
+            if self.pin.is_high().map_err(|_| SensorError::Bus)? == high {
+                return Ok(());
+            }
+            delay.delay_us(1u8);
+        }
+        Err(SensorError::Timeout)
+    }
+
+    /// Median of the last three readings, a single spike never gets through
+    fn filtered(&self) -> (i16, u16) {
+        let [a, b, c] = self.window;
+        if self.count < 3 {
+            //too few readings yet to outvote a spike
+            return a;
+        }
+        (median(a.0, b.0, c.0), median(a.1, b.1, c.1))
+    }
+}
+
+fn median<T: Ord + Copy>(a: T, b: T, c: T) -> T {
+    core::cmp::max(core::cmp::min(a, b), core::cmp::min(core::cmp::max(a, b), c))
+}
+
+impl<P, E> Sensor for DhtSensor<P>
+where
+    P: InputPin<Error = E> + OutputPin<Error = E>,
+{
+    fn channels(&self) -> &'static [(u8, &'static str)] {
+        &[(b't', "Temperature"), (b'h', "Humidity")]
+    }
+
+    /// Failed reads leave the previous values valid while retries remain,
+    /// the error only comes out once MAX_RETRIES retries failed too
+    fn sample(&mut self, delay: &mut SysDelay, now: u32) -> nb::Result<Vec<Value, MAX_VALUES>, SensorError> {
+        if !self.ready(now) {
+            return Err(nb::Error::WouldBlock);
+        }
+        self.last_read = Some(now);
+        let result = self.read(delay).and_then(|data| {
+            let (temperature, humidity) = self.model.decode(&data);
+            if self.model.is_plausible(temperature, humidity) {
+                //both fit after the range check
+                Ok((temperature as i16, humidity as u16))
+            } else {
+                Err(SensorError::Implausible)
+            }
+        });
+        let (temperature, humidity) = match result {
+            Ok(reading) => reading,
+            Err(e) => {
+                self.failures = self.failures.saturating_add(1);
+                if self.failures <= MAX_RETRIES {
+                    return Err(nb::Error::WouldBlock);
+                }
+                return Err(nb::Error::Other(e));
+            }
+        };
+        self.failures = 0;
+        self.window = [(temperature, humidity), self.window[0], self.window[1]];
+        self.count = (self.count + 1).min(3);
+
+        let (temperature, humidity) = self.filtered();
+        let mut values = Vec::new();
+        values.push(Value::Temperature(temperature.saturating_add(self.offset))).ok();
+        values.push(Value::Humidity(humidity)).ok();
+        Ok(values)
+    }
+}
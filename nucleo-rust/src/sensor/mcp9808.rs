@@ -0,0 +1,77 @@
+use embedded_hal::blocking::i2c::{Write, WriteRead};
+use heapless::Vec;
+use mcp9808::{Alert, Limit, Mcp9808, Resolution, MANUFACTURER_ID};
+use stm32f1xx_hal::timer::SysDelay;
+
+use super::{Sensor, SensorError, Value, MAX_VALUES};
+
+/// Resolution and alert window requested by the host, applied by the main loop
+#[derive(Copy, Clone)]
+pub struct Settings {
+    pub resolution: Resolution,
+    /// centi-degrees Celsius
+    pub upper: i16,
+    pub lower: i16,
+    pub critical: i16,
+}
+
+impl Settings {
+    pub const fn new() -> Settings {
+        Settings {
+            resolution: Resolution::Sixteenth,
+            upper: 3000,
+            lower: 1000,
+            critical: 5000,
+        }
+    }
+}
+
+/// MCP9808 precision temperature sensor on I2C
+pub struct Mcp9808Sensor<I2C> {
+    dev: Mcp9808<I2C>,
+    alert: Alert,
+}
+
+impl<I2C, E> Mcp9808Sensor<I2C>
+where
+    I2C: Write<Error = E> + WriteRead<Error = E>,
+{
+    pub fn new(i2c: I2C, address: u8) -> Mcp9808Sensor<I2C> {
+        Mcp9808Sensor { dev: Mcp9808::new(i2c, address), alert: Alert::default() }
+    }
+
+    /// true if a device answers with the MCP9808 manufacturer ID
+    pub fn probe(&mut self) -> bool {
+        matches!(self.dev.manufacturer_id(), Ok(MANUFACTURER_ID))
+    }
+
+    pub fn configure(&mut self, settings: &Settings) -> Result<(), SensorError> {
+        self.dev.set_resolution(settings.resolution).map_err(|_| SensorError::Bus)?;
+        self.dev.set_limit(Limit::Upper, settings.upper).map_err(|_| SensorError::Bus)?;
+        self.dev.set_limit(Limit::Lower, settings.lower).map_err(|_| SensorError::Bus)?;
+        self.dev.set_limit(Limit::Critical, settings.critical).map_err(|_| SensorError::Bus)?;
+        self.dev.enable_alert().map_err(|_| SensorError::Bus)
+    }
+
+    /// window flags latched by the last sample
+    pub fn alert(&self) -> Alert {
+        self.alert
+    }
+}
+
+impl<I2C, E> Sensor for Mcp9808Sensor<I2C>
+where
+    I2C: Write<Error = E> + WriteRead<Error = E>,
+{
+    fn channels(&self) -> &'static [(u8, &'static str)] {
+        &[(b'p', "Precise temp")]
+    }
+
+    fn sample(&mut self, _delay: &mut SysDelay, _now: u32) -> nb::Result<Vec<Value, MAX_VALUES>, SensorError> {
+        let temperature = self.dev.temperature().map_err(|_| SensorError::Bus)?;
+        self.alert = temperature.alert;
+        let mut values = Vec::new();
+        values.push(Value::Temperature(temperature.centi)).ok();
+        Ok(values)
+    }
+}
@@ -0,0 +1,202 @@
+pub mod analog;
+pub mod dht;
+pub mod mcp9808;
+pub mod mq7;
+
+use core::fmt;
+use heapless::Vec;
+use stm32f1xx_hal::timer::SysDelay;
+
+use crate::climate::Climate;
+use crate::fixed::{Quantity, Style, Unit};
+
+/// Most quantities a single sensor reports in one sample (analog scan: two spare inputs, MCU temperature, VREFINT)
+pub const MAX_VALUES: usize = 4;
+/// Most channels the registry can hold
+pub const MAX_CHANNELS: usize = 12;
+
+/// Typed physical quantity, stored in fixed point
+#[derive(Copy, Clone, PartialEq)]
+pub enum Value {
+    /// centi-degrees Celsius
+    Temperature(i16),
+    /// centi-percent relative humidity
+    Humidity(u16),
+    /// CO concentration, ppm
+    Gas(u16),
+    /// water vapour density, centi-grams per cubic metre
+    AbsoluteHumidity(u16),
+    /// millivolts
+    Voltage(u16),
+    /// unconverted ADC counts
+    Raw(u16),
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum SensorError {
+    Timeout,
+    Checksum,
+    Bus,
+    /// out of the sensor's measuring range
+    Implausible,
+}
+
+#[derive(Copy, Clone)]
+pub struct Reading {
+    pub value: Value,
+    /// seconds since boot at which the value was sampled
+    pub timestamp: u32,
+    /// RTC time of the sample, see clock::DateTime
+    pub time: u32,
+}
+
+pub struct Channel {
+    /// argument byte selecting this channel in ReadSensors, e.g. b't'
+    pub code: u8,
+    pub name: &'static str,
+    /// last successful reading, kept after a failure so it can still be shown as stale
+    pub reading: Option<Reading>,
+    /// error of the latest sample attempt, cleared by the next success
+    pub error: Option<SensorError>,
+}
+
+impl Channel {
+    pub fn is_valid(&self) -> bool {
+        self.reading.is_some() && self.error.is_none()
+    }
+}
+
+pub trait Sensor {
+    /// (code, name) of each channel this sensor feeds, in the order `sample` returns values
+    fn channels(&self) -> &'static [(u8, &'static str)];
+
+    /// nb::Error::WouldBlock means no new sample is due, previous readings stay valid
+    fn sample(&mut self, delay: &mut SysDelay, now: u32) -> nb::Result<Vec<Value, MAX_VALUES>, SensorError>;
+}
+
+pub struct Registry {
+    channels: Vec<Channel, MAX_CHANNELS>,
+}
+
+impl Registry {
+    pub const fn new() -> Registry {
+        Registry { channels: Vec::new() }
+    }
+
+    /// Adds channels of a sensor, fails if the registry is full or a code is taken
+    pub fn register(&mut self, sensor: &dyn Sensor) -> Result<(), ()> {
+        for &(code, name) in sensor.channels() {
+            if self.get(code).is_some() {
+                return Err(());
+            }
+            self.channels
+                .push(Channel { code, name, reading: None, error: None })
+                .map_err(|_| ())?;
+        }
+        Ok(())
+    }
+
+    /// Stores the outcome of `Sensor::sample` into the sensor's channels
+    pub fn store(&mut self, channels: &[(u8, &'static str)], result: nb::Result<Vec<Value, MAX_VALUES>, SensorError>, now: u32, time: u32) {
+        match result {
+            Ok(values) => {
+                for (&(code, _), value) in channels.iter().zip(values.iter()) {
+                    if let Some(channel) = self.get_mut(code) {
+                        channel.reading = Some(Reading { value: *value, timestamp: now, time });
+                        channel.error = None;
+                    }
+                }
+            }
+            Err(nb::Error::Other(e)) => {
+                for &(code, _) in channels {
+                    if let Some(channel) = self.get_mut(code) {
+                        channel.error = Some(e);
+                    }
+                }
+            }
+            Err(nb::Error::WouldBlock) => {}
+        }
+    }
+
+    pub fn get(&self, code: u8) -> Option<&Channel> {
+        self.channels.iter().find(|c| c.code == code)
+    }
+
+    pub fn get_mut(&mut self, code: u8) -> Option<&mut Channel> {
+        self.channels.iter_mut().find(|c| c.code == code)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Channel> {
+        self.channels.iter()
+    }
+
+    /// Derived metrics from the latest valid readings, the MCP9808 temperature is preferred over the DHT one
+    pub fn climate(&self) -> Option<Climate> {
+        let valid = |code| self.get(code).filter(|c| c.is_valid()).and_then(|c| c.reading).map(|r| r.value);
+        let temperature = match valid(b'p').or_else(|| valid(b't'))? {
+            Value::Temperature(t) => t,
+            _ => return None,
+        };
+        match valid(b'h')? {
+            Value::Humidity(h) => Climate::new(temperature, h),
+            _ => None,
+        }
+    }
+}
+
+impl Value {
+    /// value in hundredths of its unit
+    pub fn centi(&self) -> i32 {
+        match *self {
+            Value::Temperature(t) => t as i32,
+            Value::Humidity(h) => h as i32,
+            Value::Gas(g) => g as i32 * 100,
+            Value::AbsoluteHumidity(a) => a as i32,
+            Value::Voltage(v) => v as i32 / 10,
+            Value::Raw(r) => r as i32 * 100,
+        }
+    }
+
+    /// value as stored, in the unit alarm thresholds use
+    pub fn as_i32(&self) -> i32 {
+        match *self {
+            Value::Temperature(t) => t as i32,
+            Value::Humidity(h) => h as i32,
+            Value::Gas(g) => g as i32,
+            Value::AbsoluteHumidity(a) => a as i32,
+            Value::Voltage(v) => v as i32,
+            Value::Raw(r) => r as i32,
+        }
+    }
+
+    /// same quantity with another stored value, e.g. for statistics of a channel
+    pub fn like(&self, value: i32) -> Value {
+        match *self {
+            Value::Temperature(_) => Value::Temperature(value as i16),
+            Value::Humidity(_) => Value::Humidity(value as u16),
+            Value::Gas(_) => Value::Gas(value as u16),
+            Value::AbsoluteHumidity(_) => Value::AbsoluteHumidity(value as u16),
+            Value::Voltage(_) => Value::Voltage(value as u16),
+            Value::Raw(_) => Value::Raw(value as u16),
+        }
+    }
+
+    /// value with its unit, written as `style` asks
+    pub fn styled(&self, style: Style) -> Quantity {
+        match *self {
+            Value::Temperature(t) => Quantity::temperature(t as i32, style),
+            Value::Humidity(_) => Quantity { hundredths: self.centi(), unit: Unit::Percent, style },
+            Value::Gas(_) => Quantity { hundredths: self.centi(), unit: Unit::Ppm, style },
+            Value::AbsoluteHumidity(_) => Quantity { hundredths: self.centi(), unit: Unit::GramsPerCubicMetre, style },
+            Value::Voltage(_) => Quantity { hundredths: self.centi(), unit: Unit::Volts, style },
+            Value::Raw(_) => Quantity { hundredths: self.centi(), unit: Unit::Raw, style },
+        }
+    }
+}
+
+/// Style::DEFAULT: one decimal in Celsius for temperature and humidity, integers for ppm and counts
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.styled(Style::DEFAULT), f)
+    }
+}
@@ -0,0 +1,207 @@
+use embedded_hal::digital::v2::OutputPin;
+use heapless::Vec;
+use stm32f1xx_hal::timer::SysDelay;
+
+use super::{Sensor, SensorError, Value, MAX_VALUES};
+
+/// ADC full scale, the reference is VDDA as measured by the analog scan
+const ADC_MAX: u32 = 4095;
+/// sensor module supply and load resistor, output wired straight to the ADC pin
+const SUPPLY_MV: u32 = 5000;
+const LOAD_RESISTANCE: u32 = 10_000;
+/// Rs/R0 in clean air, x1000
+const CLEAN_AIR_RATIO: u32 = 27_500;
+/// R0 used until the sensor is calibrated
+pub const DEFAULT_R0: u32 = 10_000;
+/// heater cycles averaged by a calibration
+const CALIBRATION_CYCLES: u32 = 3;
+
+const HIGH_PHASE_SECONDS: u32 = 60;
+const LOW_PHASE_SECONDS: u32 = 90;
+
+/// (Rs/R0 x1000, ppm) points of the datasheet CO curve, ppm = 99.042 * (Rs/R0)^-1.518
+const CO_CURVE: [(u32, u16); 14] = [
+    (2869, 20),
+    (2196, 30),
+    (1569, 50),
+    (1257, 70),
+    (994, 100),
+    (761, 150),
+    (629, 200),
+    (482, 300),
+    (399, 400),
+    (305, 600),
+    (253, 800),
+    (218, 1000),
+    (167, 1500),
+    (138, 2000),
+];
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum HeaterPhase {
+    /// 5 V purge
+    High,
+    /// 1.4 V measurement
+    Low,
+}
+
+#[derive(Copy, Clone)]
+pub struct Status {
+    pub r0: u32,
+    pub calibrated: bool,
+    /// heater cycles left in a running calibration
+    pub calibrating: u32,
+    pub phase: HeaterPhase,
+    /// seconds left in the current heater phase
+    pub remaining: u32,
+}
+
+/// MQ-7 CO sensor on A0, its counts come from the analog scan; the heater pin selects 5 V when high and 1.4 V when low
+pub struct Mq7Sensor<HEATER> {
+    /// counts of the latest analog scan and the VDDA in mV they were taken at
+    input: Option<(u16, u16)>,
+    heater: HEATER,
+    phase: HeaterPhase,
+    phase_start: u32,
+    r0: u32,
+    calibrated: bool,
+    calibrating: u32,
+    calibration_sum: u32,
+    /// R0 of a finished calibration not yet persisted
+    new_r0: Option<u32>,
+}
+
+impl<HEATER> Mq7Sensor<HEATER>
+where
+    HEATER: OutputPin,
+{
+    /// r0 is a stored calibration, None falls back to DEFAULT_R0
+    pub fn new(mut heater: HEATER, r0: Option<u32>, now: u32) -> Mq7Sensor<HEATER> {
+        heater.set_high().ok();
+        Mq7Sensor {
+            input: None,
+            heater,
+            phase: HeaterPhase::High,
+            phase_start: now,
+            r0: r0.unwrap_or(DEFAULT_R0),
+            calibrated: r0.is_some(),
+            calibrating: 0,
+            calibration_sum: 0,
+            new_r0: None,
+        }
+    }
+
+    /// Counts of the analog scan and VDDA in mV, taken by the next sample at the end of the low phase
+    pub fn set_input(&mut self, counts: u16, vdda: u16) {
+        self.input = Some((counts, vdda));
+    }
+
+    /// Starts an R0 calibration; the sensor must sit in clean air for the next heater cycles
+    pub fn start_calibration(&mut self) {
+        self.calibrating = CALIBRATION_CYCLES;
+        self.calibration_sum = 0;
+    }
+
+    /// R0 of a calibration that finished since the last call
+    pub fn take_calibration(&mut self) -> Option<u32> {
+        self.new_r0.take()
+    }
+
+    pub fn status(&self, now: u32) -> Status {
+        let length = match self.phase {
+            HeaterPhase::High => HIGH_PHASE_SECONDS,
+            HeaterPhase::Low => LOW_PHASE_SECONDS,
+        };
+        Status {
+            r0: self.r0,
+            calibrated: self.calibrated,
+            calibrating: self.calibrating,
+            phase: self.phase,
+            remaining: length.saturating_sub(now.wrapping_sub(self.phase_start)),
+        }
+    }
+
+    /// Runs the 60 s high / 90 s low heater cycle on RTC seconds, called every tick whatever the sample interval;
+    /// true once the low phase is over and the next sample measures
+    pub fn advance(&mut self, now: u32) -> bool {
+        let elapsed = now.wrapping_sub(self.phase_start);
+        match self.phase {
+            HeaterPhase::High if elapsed >= HIGH_PHASE_SECONDS => {
+                self.heater.set_low().ok();
+                self.phase = HeaterPhase::Low;
+                self.phase_start = now;
+                false
+            }
+            HeaterPhase::High => false,
+            HeaterPhase::Low => elapsed >= LOW_PHASE_SECONDS,
+        }
+    }
+
+    fn calibrate(&mut self, rs: u32) {
+        self.calibration_sum += rs / CALIBRATION_CYCLES;
+        self.calibrating -= 1;
+        if self.calibrating == 0 {
+            let r0 = (self.calibration_sum as u64 * 1000 / CLEAN_AIR_RATIO as u64) as u32;
+            self.r0 = r0.max(1);
+            self.calibrated = true;
+            self.new_r0 = Some(self.r0);
+        }
+    }
+}
+
+/// sensor resistance in ohms from ADC counts taken at `vdda` mV
+fn resistance(raw: u16, vdda: u16) -> u32 {
+    let vout = raw as u32 * vdda as u32 / ADC_MAX;
+    if vout == 0 {
+        return u32::MAX;
+    }
+    LOAD_RESISTANCE * (SUPPLY_MV.saturating_sub(vout)) / vout
+}
+
+/// CO concentration from Rs/R0 x1000, clamped to the 20..2000 ppm curve
+fn ppm(ratio: u32) -> u16 {
+    if ratio >= CO_CURVE[0].0 {
+        return CO_CURVE[0].1;
+    }
+    for pair in CO_CURVE.windows(2) {
+        let (r_hi, ppm_lo) = pair[0];
+        let (r_lo, ppm_hi) = pair[1];
+        if ratio >= r_lo {
+            //linear between neighbouring curve points
+            let span = (ppm_hi - ppm_lo) as u32;
+            return ppm_lo + ((r_hi - ratio) * span / (r_hi - r_lo)) as u16;
+        }
+    }
+    CO_CURVE[CO_CURVE.len() - 1].1
+}
+
+impl<HEATER> Sensor for Mq7Sensor<HEATER>
+where
+    HEATER: OutputPin,
+{
+    fn channels(&self) -> &'static [(u8, &'static str)] {
+        &[(b'g', "Gas"), (b'a', "Gas raw")]
+    }
+
+    /// Measures only at the end of the low phase, `advance` runs the heater cycle up to it
+    fn sample(&mut self, _delay: &mut SysDelay, now: u32) -> nb::Result<Vec<Value, MAX_VALUES>, SensorError> {
+        if self.phase != HeaterPhase::Low || now.wrapping_sub(self.phase_start) < LOW_PHASE_SECONDS {
+            return Err(nb::Error::WouldBlock);
+        }
+        let (raw, vdda) = self.input.take().ok_or(SensorError::Timeout)?;
+        self.heater.set_high().map_err(|_| SensorError::Bus)?;
+        self.phase = HeaterPhase::High;
+        self.phase_start = now;
+
+        let rs = resistance(raw, vdda);
+        if self.calibrating > 0 {
+            self.calibrate(rs);
+        }
+        let ratio = (rs as u64 * 1000 / self.r0 as u64).min(u32::MAX as u64) as u32;
+
+        let mut values = Vec::new();
+        values.push(Value::Gas(ppm(ratio))).ok();
+        values.push(Value::Raw(raw)).ok();
+        Ok(values)
+    }
+}
@@ -0,0 +1,171 @@
+use core::fmt::{self, Write};
+use heapless::Vec;
+
+use crate::command::Port;
+use crate::crc::crc16;
+use crate::fixed::{Fixed, Style};
+use crate::sensor::Registry;
+
+/// Largest record, a text line with every channel of a full registry
+pub const RECORD_SIZE: usize = 320;
+/// First byte of a binary frame
+const SYNC: u8 = 0xA5;
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum Format {
+    /// `seq: name value, ...` with units in the configured style
+    Text,
+    /// `seq,time,value,...`, a header line names the columns when streaming starts
+    Csv,
+    /// one JSON object per line keyed by channel code
+    Json,
+    /// SYNC, length, seq u16, time u32, (code, centi i32) per channel, CRC-16/MODBUS, little-endian
+    Binary,
+}
+
+impl Format {
+    /// Format selected by a command argument
+    pub fn from_code(code: u8) -> Option<Format> {
+        match code {
+            b't' => Some(Format::Text),
+            b'c' => Some(Format::Csv),
+            b'j' => Some(Format::Json),
+            b'b' => Some(Format::Binary),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Format::Text => "text",
+            Format::Csv => "CSV",
+            Format::Json => "JSON",
+            Format::Binary => "binary",
+        }
+    }
+}
+
+/// Telemetry subscription, pushes the latest readings every `interval` seconds until stopped
+pub struct Stream {
+    pub format: Format,
+    /// seconds between records, 0 while stopped
+    pub interval: u16,
+    /// sequence number of the next record, a gap on the host side means lost records
+    pub seq: u16,
+    /// port that started the stream, records go out there
+    pub port: Port,
+    /// uptime of the last record
+    last: u32,
+    /// the CSV header still has to go out
+    header: bool,
+}
+
+impl Stream {
+    pub const fn new() -> Stream {
+        Stream { format: Format::Text, interval: 0, seq: 0, port: Port::Uart, last: 0, header: false }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.interval > 0
+    }
+
+    /// Starts streaming from sequence number 0, the first record goes out on the next tick
+    pub fn start(&mut self, format: Format, interval: u16, now: u32, port: Port) {
+        self.format = format;
+        self.port = port;
+        self.interval = interval.max(1);
+        self.seq = 0;
+        self.last = now.wrapping_sub(self.interval as u32);
+        self.header = format == Format::Csv;
+    }
+
+    pub fn stop(&mut self) {
+        self.interval = 0;
+    }
+
+    /// Formats the record due at `now`, if any; `time` is the RTC counter, `style` only applies to text records.
+    /// The sequence number advances even if the caller can't send the record
+    pub fn record(&mut self, sensors: &Registry, style: Style, now: u32, time: u32) -> Option<Vec<u8, RECORD_SIZE>> {
+        if !self.is_active() || now.wrapping_sub(self.last) < self.interval as u32 {
+            return None;
+        }
+        self.last = now;
+        let mut record = Record(Vec::new());
+        let _res = match self.format {
+            Format::Text => self.text(&mut record, sensors, style),
+            Format::Csv => self.csv(&mut record, sensors, time),
+            Format::Json => self.json(&mut record, sensors, now, time),
+            Format::Binary => self.binary(&mut record, sensors, time),
+        };
+        self.seq = self.seq.wrapping_add(1);
+        Some(record.0)
+    }
+
+    fn text(&self, out: &mut Record, sensors: &Registry, style: Style) -> fmt::Result {
+        write!(out, "{}:", self.seq)?;
+        for (i, channel) in sensors.iter().enumerate() {
+            let separator = if i == 0 { " " } else { ", " };
+            match channel.reading {
+                Some(reading) if channel.error.is_none() => write!(out, "{}{} {}", separator, channel.name, reading.value.styled(style))?,
+                _ => write!(out, "{}{} --", separator, channel.name)?,
+            }
+        }
+        write!(out, "\r\n")
+    }
+
+    fn csv(&mut self, out: &mut Record, sensors: &Registry, time: u32) -> fmt::Result {
+        if core::mem::replace(&mut self.header, false) {
+            write!(out, "seq,time")?;
+            for channel in sensors.iter() {
+                write!(out, ",{}", channel.name)?;
+            }
+            write!(out, "\r\n")?;
+        }
+        write!(out, "{},{}", self.seq, time)?;
+        for channel in sensors.iter() {
+            match channel.reading {
+                Some(reading) if channel.error.is_none() => write!(out, ",{}", Fixed::new(reading.value.centi(), 2))?,
+                _ => write!(out, ",")?,
+            }
+        }
+        write!(out, "\r\n")
+    }
+
+    fn json(&self, out: &mut Record, sensors: &Registry, now: u32, time: u32) -> fmt::Result {
+        write!(out, "{{\"seq\":{},\"time\":{},\"uptime\":{}", self.seq, time, now)?;
+        for channel in sensors.iter() {
+            match channel.reading {
+                Some(reading) if channel.error.is_none() => write!(out, ",\"{}\":{}", channel.code as char, Fixed::new(reading.value.centi(), 2))?,
+                _ => write!(out, ",\"{}\":null", channel.code as char)?,
+            }
+        }
+        write!(out, "}}\r\n")
+    }
+
+    /// Channels without a valid reading are left out of the frame
+    fn binary(&self, out: &mut Record, sensors: &Registry, time: u32) -> fmt::Result {
+        let frame = &mut out.0;
+        frame.extend_from_slice(&[SYNC, 0]).map_err(|_| fmt::Error)?;
+        frame.extend_from_slice(&self.seq.to_le_bytes()).map_err(|_| fmt::Error)?;
+        frame.extend_from_slice(&time.to_le_bytes()).map_err(|_| fmt::Error)?;
+        for channel in sensors.iter() {
+            if let (Some(reading), None) = (channel.reading, channel.error) {
+                frame.push(channel.code).map_err(|_| fmt::Error)?;
+                frame.extend_from_slice(&reading.value.centi().to_le_bytes()).map_err(|_| fmt::Error)?;
+            }
+        }
+        //length counts everything after the length byte up to the CRC
+        frame[1] = (frame.len() - 2) as u8;
+        let crc = crc16(frame);
+        frame.extend_from_slice(&crc.to_le_bytes()).map_err(|_| fmt::Error)
+    }
+}
+
+/// Formatting target for a record, what doesn't fit is cut off
+struct Record(Vec<u8, RECORD_SIZE>);
+
+impl Write for Record {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0.extend_from_slice(s.as_bytes()).map_err(|_| fmt::Error)
+    }
+}
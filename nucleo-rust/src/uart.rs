@@ -0,0 +1,93 @@
+use core::fmt;
+use heapless::spsc::{Consumer, Producer, Queue};
+use stm32f1xx_hal::{pac::{Interrupt, USART2}, serial::Tx};
+
+/// Transmit buffer, heapless queues keep one slot free so 511 bytes can wait
+pub const TX_BUFFER_SIZE: usize = 512;
+
+pub type TxQueue = Queue<u8, TX_BUFFER_SIZE>;
+
+/// Non-blocking formatted output, bytes are queued for the interrupt of the port to send
+pub struct SerialOut {
+    queue: Producer<'static, u8, TX_BUFFER_SIZE>,
+    /// bytes dropped because the buffer was full
+    overflow: u32,
+    /// text output is dropped, the line carries Modbus frames only
+    silent: bool,
+    /// interrupt that sends the queued bytes
+    interrupt: Interrupt,
+}
+
+impl SerialOut {
+    pub fn new(queue: Producer<'static, u8, TX_BUFFER_SIZE>, interrupt: Interrupt) -> SerialOut {
+        SerialOut { queue, overflow: 0, silent: false, interrupt }
+    }
+
+    /// Drops formatted text from now on, `write_all` still sends
+    pub fn set_silent(&mut self, silent: bool) {
+        self.silent = silent;
+    }
+
+    /// true once the interrupt handler has taken every buffered byte
+    pub fn is_empty(&self) -> bool {
+        self.queue.len() == 0
+    }
+
+    /// Queues `bytes` only if all of them fit, so a record is never cut short; false if dropped
+    pub fn write_all(&mut self, bytes: &[u8]) -> bool {
+        if self.queue.capacity() - self.queue.len() < bytes.len() {
+            return false;
+        }
+        for &byte in bytes {
+            let _res = self.queue.enqueue(byte);
+        }
+        rtic::pend(self.interrupt);
+        true
+    }
+
+    /// Dropped byte count since the last call
+    pub fn take_overflow(&mut self) -> u32 {
+        core::mem::replace(&mut self.overflow, 0)
+    }
+}
+
+impl fmt::Write for SerialOut {
+    /// Never blocks or fails, what doesn't fit in the buffer is counted and dropped
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if self.silent {
+            return Ok(());
+        }
+        for byte in s.bytes() {
+            if self.queue.enqueue(byte).is_err() {
+                self.overflow = self.overflow.wrapping_add(1);
+            }
+        }
+        //the interrupt handler starts transmitting, USART2 enables TXE while bytes remain
+        rtic::pend(self.interrupt);
+        Ok(())
+    }
+}
+
+/// Transmit half of the USART2 interrupt, feeds the data register from the buffer
+pub struct SerialDrain {
+    tx: Tx<USART2>,
+    queue: Consumer<'static, u8, TX_BUFFER_SIZE>,
+}
+
+impl SerialDrain {
+    pub fn new(tx: Tx<USART2>, queue: Consumer<'static, u8, TX_BUFFER_SIZE>) -> SerialDrain {
+        SerialDrain { tx, queue }
+    }
+
+    /// Sends queued bytes while the data register is empty, leaves TXE listened only while bytes remain
+    pub fn drain(&mut self) {
+        while let Some(&byte) = self.queue.peek() {
+            if self.tx.write(byte).is_err() {
+                self.tx.listen();
+                return;
+            }
+            self.queue.dequeue();
+        }
+        self.tx.unlisten();
+    }
+}
@@ -0,0 +1,65 @@
+use cortex_m::peripheral::NVIC;
+use heapless::{Vec, spsc::Consumer};
+use stm32f1xx_hal::{pac::Interrupt, usb::UsbBusType};
+use usb_device::{UsbError, device::UsbDevice};
+use usbd_serial::SerialPort;
+
+use crate::uart::TX_BUFFER_SIZE;
+
+/// Full speed bulk endpoint size
+const PACKET_SIZE: usize = 64;
+
+pub type UsbDev = UsbDevice<'static, UsbBusType>;
+pub type UsbSerial = SerialPort<'static, UsbBusType>;
+
+/// Transmit half of the USB interrupt, hands queued bytes to the CDC class a packet at a time
+pub struct UsbDrain {
+    queue: Consumer<'static, u8, TX_BUFFER_SIZE>,
+    /// bytes taken from the queue the class hasn't accepted yet
+    packet: Vec<u8, PACKET_SIZE>,
+}
+
+impl UsbDrain {
+    pub fn new(queue: Consumer<'static, u8, TX_BUFFER_SIZE>) -> UsbDrain {
+        UsbDrain { queue, packet: Vec::new() }
+    }
+
+    /// Writes until the class buffer is full, the next IN transfer interrupt continues
+    pub fn drain(&mut self, serial: &mut UsbSerial) {
+        loop {
+            while self.packet.len() < PACKET_SIZE {
+                match self.queue.dequeue() {
+                    Some(byte) => self.packet.push(byte).unwrap(),
+                    None => break,
+                }
+            }
+            if self.packet.is_empty() {
+                return;
+            }
+            match serial.write(&self.packet) {
+                Ok(written) => {
+                    let rest = self.packet.len() - written;
+                    self.packet.copy_within(written.., 0);
+                    self.packet.truncate(rest);
+                }
+                Err(UsbError::WouldBlock) => return,
+                //not configured or gone, the output is lost
+                Err(_) => {
+                    self.packet.clear();
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// Runs `f` with the USB interrupt masked, for bit timing a USB poll in between would break.
+/// The peripheral NAKs the host meanwhile and the host retries, nothing is lost over a few ms
+#[allow(unsafe_code)]
+pub fn without_interrupt<R>(f: impl FnOnce() -> R) -> R {
+    NVIC::mask(Interrupt::USB_LP_CAN_RX0);
+    let result = f();
+    //RTIC locks use BASEPRI on the Cortex-M3, unmasking can't end one early
+    unsafe { NVIC::unmask(Interrupt::USB_LP_CAN_RX0) };
+    result
+}